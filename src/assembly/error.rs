@@ -0,0 +1,183 @@
+use crate::assembly::Span;
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    DivisionByZero,
+    UnknownMnemonic(String),
+    BadOperand(String),
+    // Operand that can be parsed but not used in its position
+    IllegalOperand(String),
+    TrailingGarbage(String),
+    MissingOperand,
+    MissingSemicolon,
+    // Name in .if or .rep that isn't a .define known at that point
+    NotConstant(String),
+    UnterminatedBlock(String),
+    UnexpectedDirective(String),
+    DuplicateMacro(String),
+    MacroArguments {
+        name: String,
+        expected: usize,
+        found: usize
+    },
+    MacroDepth(String),
+    FileNotFound(String),
+    CantRead {
+        path: String,
+        message: String
+    },
+    IncludeCycle(String),
+    LocalOutsideScope(String),
+    // Two sections use the same address, `other` is where the first one is
+    Overlap {
+        address: u16,
+        other: String
+    },
+    // Statement that runs past the last address
+    AddressOverflow,
+    // Labels keep moving because the layout depends on them, e.g.
+    // `.fill 1 - end, 0` right before `end:`
+    LayoutDidNotConverge,
+    // .extern in a program assembled straight to an image
+    UnlinkedImport(String),
+    // Expression in an object file that isn't a constant or a single
+    // symbol plus a constant
+    NotRelocatable(String)
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UndefinedLabel(label) => write!(f, "undefined label `{}`", label),
+            ErrorKind::DuplicateLabel(label) => write!(f, "label `{}` is defined more than once", label),
+            ErrorKind::DivisionByZero => write!(f, "division by zero in constant expression"),
+            ErrorKind::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic `{}`", mnemonic),
+            ErrorKind::BadOperand(operand) => write!(f, "bad operand `{}`", operand),
+            ErrorKind::IllegalOperand(reason) => write!(f, "illegal operand: {}", reason),
+            ErrorKind::TrailingGarbage(garbage) => write!(f, "unexpected `{}` after statement", garbage),
+            ErrorKind::MissingOperand => write!(f, "missing operand"),
+            ErrorKind::MissingSemicolon => write!(f, "statement doesn't end with `;`"),
+            ErrorKind::NotConstant(name) => write!(f, "`{}` is not a constant defined before this point", name),
+            ErrorKind::UnterminatedBlock(directive) => write!(f, "`{}` block is never closed", directive),
+            ErrorKind::UnexpectedDirective(directive) => write!(f, "unexpected `{}`", directive),
+            ErrorKind::DuplicateMacro(name) => write!(f, "macro `{}` is defined more than once", name),
+            ErrorKind::MacroArguments { name, expected, found } =>
+                write!(f, "macro `{}` takes {} arguments but {} were given", name, expected, found),
+            ErrorKind::MacroDepth(name) => write!(f, "expansion of macro `{}` is nested too deeply, is it recursive?", name),
+            ErrorKind::FileNotFound(name) => write!(f, "can't find `{}` next to this file or in the include paths", name),
+            ErrorKind::CantRead { path, message } => write!(f, "can't read `{}`: {}", path, message),
+            ErrorKind::IncludeCycle(path) => write!(f, "`{}` is already being included, includes can't be cyclic", path),
+            ErrorKind::LocalOutsideScope(label) => write!(f, "local label `{}` comes before any global label", label),
+            ErrorKind::Overlap { address, other } => write!(f, "address {:#06x} is already used by {}", address, other),
+            ErrorKind::AddressOverflow => write!(f, "program exceeds 64K words of memory"),
+            ErrorKind::LayoutDidNotConverge => write!(f, "addresses never settle, the layout depends on labels it moves"),
+            ErrorKind::UnlinkedImport(name) => write!(f, "`{}` is imported, assemble an object file and link it", name),
+            ErrorKind::NotRelocatable(expr) => write!(f, "`{}` can't be relocated, use a symbol plus or minus a constant", expr)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    pub span: Span,
+    pub kind: ErrorKind
+}
+
+impl AssembleError {
+    pub fn new(span: Span, kind: ErrorKind) -> AssembleError {
+        AssembleError { span, kind }
+    }
+}
+
+// Notes shown for the macro calls an error was expanded from, recursive
+// macros can nest much deeper
+const MAX_EXPANSION_NOTES: usize = 4;
+
+// file:line:column: error: message
+//     source line
+//         ^
+// followed by a note for the macro calls the line was expanded from,
+// innermost first. Nested calls from the same place share a note.
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: error: {}", self.span, self.kind)?;
+        self.span.fmt_snippet(f)?;
+        let mut calls: Vec<(&Span, usize)> = vec![];
+        let mut call = &self.span.expanded_from;
+        while let Some(span) = call {
+            match calls.last_mut() {
+                Some((last, count)) if (&last.file, last.line, last.column) == (&span.file, span.line, span.column) => *count += 1,
+                _ => calls.push((span, 1))
+            }
+            call = &span.expanded_from;
+        }
+        for (span, count) in calls.iter().take(MAX_EXPANSION_NOTES) {
+            writeln!(f)?;
+            match count {
+                1 => writeln!(f, "{}: note: in expansion of this macro call", span)?,
+                _ => writeln!(f, "{}: note: in {} nested expansions of this macro call", span, count)?
+            }
+            span.fmt_snippet(f)?;
+        }
+        if calls.len() > MAX_EXPANSION_NOTES {
+            writeln!(f)?;
+            write!(f, "note: and {} more macro calls", calls.len() - MAX_EXPANSION_NOTES)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    // Symbol an object imports or relocates against that nothing defines
+    UndefinedSymbol {
+        symbol: String,
+        object: String
+    },
+    DuplicateSymbol {
+        symbol: String,
+        first: String,
+        second: String
+    },
+    // Two sections are placed at the same address
+    Overlap {
+        address: u16,
+        first: String,
+        second: String
+    },
+    // The section doesn't fit before the end of memory
+    OutOfMemory {
+        section: String,
+        object: String
+    },
+    // Relocation outside its section, the object file is broken
+    BadRelocation {
+        offset: u16,
+        section: String,
+        object: String
+    }
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::UndefinedSymbol { symbol, object } => write!(f, "{}: undefined symbol `{}`", object, symbol),
+            LinkError::DuplicateSymbol { symbol, first, second } =>
+                write!(f, "symbol `{}` is exported by both {} and {}", symbol, first, second),
+            LinkError::Overlap { address, first, second } =>
+                write!(f, "{} and {} both use address {:#06x}", first, second, address),
+            LinkError::OutOfMemory { section, object } =>
+                write!(f, "{}: section `{}` runs past the end of memory", object, section),
+            LinkError::BadRelocation { offset, section, object } =>
+                write!(f, "{}: relocation at {:#06x} is outside section `{}`", object, offset, section)
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
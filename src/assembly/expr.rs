@@ -0,0 +1,294 @@
+use crate::assembly::ErrorKind;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+// Constant expression, evaluated with 16 bit wraparound once labels are laid out
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(u16),
+    Label(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    // Comparisons give 1 or 0
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge
+}
+
+impl Expr {
+    // Calls `f` on the name of every label the expression uses
+    pub fn labels_mut<F: FnMut(&mut String)>(&mut self, f: &mut F) {
+        match self {
+            Expr::Number(_) => {},
+            Expr::Label(label) => f(label),
+            Expr::Unary(_, expr) => expr.labels_mut(f),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.labels_mut(f);
+                rhs.labels_mut(f);
+            }
+        }
+    }
+
+    // Whether the expression uses no labels at all
+    pub fn is_constant(&self) -> bool {
+        match self {
+            Expr::Number(_) => true,
+            Expr::Label(_) => false,
+            Expr::Unary(_, expr) => expr.is_constant(),
+            Expr::Binary(_, lhs, rhs) => lhs.is_constant() && rhs.is_constant()
+        }
+    }
+
+    pub fn eval(&self, labels: &HashMap<String, u16>) -> Result<u16, ErrorKind> {
+        match self {
+            Expr::Number(number) => Ok(*number),
+            Expr::Label(label) => match labels.get(label) {
+                Some(address) => Ok(*address),
+                None => Err(ErrorKind::UndefinedLabel(label.clone()))
+            },
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(labels)?;
+                Ok(match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => !value
+                })
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(labels)?;
+                let rhs = rhs.eval(labels)?;
+                op.apply(lhs, rhs)
+            }
+        }
+    }
+}
+
+impl Expr {
+    // Binding strength, matching the parser's operator precedence
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Number(_) | Expr::Label(_) => 8,
+            Expr::Unary(_, _) => 7,
+            Expr::Binary(op, _, _) => op.precedence()
+        }
+    }
+
+    // Writes `expr`, in parentheses if it binds weaker than `precedence`
+    fn fmt_operand(expr: &Expr, precedence: u8, f: &mut fmt::Formatter) -> fmt::Result {
+        if expr.precedence() < precedence {
+            write!(f, "({})", expr)
+        } else {
+            write!(f, "{}", expr)
+        }
+    }
+}
+
+// Prints the expression with the fewest parentheses that parse back to it
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Number(number) => write!(f, "{}", number),
+            Expr::Label(label) => write!(f, "{}", label),
+            Expr::Unary(op, expr) => {
+                write!(f, "{}", op)?;
+                Expr::fmt_operand(expr, self.precedence(), f)
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                // Operators are left associative
+                Expr::fmt_operand(lhs, op.precedence(), f)?;
+                write!(f, " {} ", op)?;
+                Expr::fmt_operand(rhs, op.precedence() + 1, f)
+            }
+        }
+    }
+}
+
+impl BinaryOp {
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => 0,
+            BinaryOp::Or => 1,
+            BinaryOp::Xor => 2,
+            BinaryOp::And => 3,
+            BinaryOp::Shl | BinaryOp::Shr => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 6
+        }
+    }
+
+    pub fn apply(&self, lhs: u16, rhs: u16) -> Result<u16, ErrorKind> {
+        Ok(match self {
+            BinaryOp::Add => lhs.wrapping_add(rhs),
+            BinaryOp::Sub => lhs.wrapping_sub(rhs),
+            BinaryOp::Mul => lhs.wrapping_mul(rhs),
+            BinaryOp::Div => lhs.checked_div(rhs).ok_or(ErrorKind::DivisionByZero)?,
+            BinaryOp::Mod => lhs.checked_rem(rhs).ok_or(ErrorKind::DivisionByZero)?,
+            BinaryOp::And => lhs & rhs,
+            BinaryOp::Or => lhs | rhs,
+            BinaryOp::Xor => lhs ^ rhs,
+            BinaryOp::Shl => lhs.checked_shl(rhs as u32).unwrap_or(0),
+            BinaryOp::Shr => lhs.checked_shr(rhs as u32).unwrap_or(0),
+            BinaryOp::Eq => (lhs == rhs) as u16,
+            BinaryOp::Ne => (lhs != rhs) as u16,
+            BinaryOp::Lt => (lhs < rhs) as u16,
+            BinaryOp::Gt => (lhs > rhs) as u16,
+            BinaryOp::Le => (lhs <= rhs) as u16,
+            BinaryOp::Ge => (lhs >= rhs) as u16
+        })
+    }
+}
+
+impl FromStr for BinaryOp {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "+" => Ok(BinaryOp::Add),
+            "-" => Ok(BinaryOp::Sub),
+            "*" => Ok(BinaryOp::Mul),
+            "/" => Ok(BinaryOp::Div),
+            "%" => Ok(BinaryOp::Mod),
+            "&" => Ok(BinaryOp::And),
+            "|" => Ok(BinaryOp::Or),
+            "^" => Ok(BinaryOp::Xor),
+            "<<" => Ok(BinaryOp::Shl),
+            ">>" => Ok(BinaryOp::Shr),
+            "==" => Ok(BinaryOp::Eq),
+            "!=" => Ok(BinaryOp::Ne),
+            "<" => Ok(BinaryOp::Lt),
+            ">" => Ok(BinaryOp::Gt),
+            "<=" => Ok(BinaryOp::Le),
+            ">=" => Ok(BinaryOp::Ge),
+            _ => Err(())
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::And => "&",
+            BinaryOp::Or => "|",
+            BinaryOp::Xor => "^",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::Le => "<=",
+            BinaryOp::Ge => ">="
+        })
+    }
+}
+
+impl FromStr for UnaryOp {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "-" => Ok(UnaryOp::Neg),
+            "~" => Ok(UnaryOp::Not),
+            _ => Err(())
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "~"
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly::parser::{parse_complete, parse_expr};
+
+    fn parse(text: &str) -> Expr {
+        let (rest, expr) = parse_complete(text, parse_expr).unwrap();
+        assert_eq!(rest.trim(), "", "`{}` wasn't parsed completely", text);
+        expr
+    }
+
+    fn value(text: &str) -> Result<u16, ErrorKind> {
+        let labels = [("start", 0x10), ("end", 0x18)].iter()
+            .map(|(label, address)| (String::from(*label), *address))
+            .collect();
+        parse(text).eval(&labels)
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(value("1 + 2 * 3"), Ok(7));
+        assert_eq!(value("(1 + 2) * 3"), Ok(9));
+        assert_eq!(value("10 - 4 - 3"), Ok(3));
+        assert_eq!(value("64 / 4 / 2"), Ok(8));
+        assert_eq!(value("1 << 2 + 1"), Ok(8));
+        assert_eq!(value("6 & 3 | 8"), Ok(10));
+        assert_eq!(value("1 | 6 ^ 3"), Ok(5));
+        assert_eq!(value("7 % 4 * 2"), Ok(6));
+        assert_eq!(value("2 * -3 + 7"), Ok(1));
+        assert_eq!(value("~0 & 0xff"), Ok(0xff));
+    }
+
+    #[test]
+    fn arithmetic_wraps_at_16_bits() {
+        assert_eq!(value("0xffff + 2"), Ok(1));
+        assert_eq!(value("0 - 1"), Ok(0xffff));
+        assert_eq!(value("-1"), Ok(0xffff));
+        assert_eq!(value("0x100 * 0x100"), Ok(0));
+        assert_eq!(value("1 << 16"), Ok(0));
+    }
+
+    #[test]
+    fn label_arithmetic() {
+        assert_eq!(value("end - start"), Ok(8));
+        assert_eq!(value("start + (end - start) / 2"), Ok(0x14));
+        assert_eq!(value("middle"), Err(ErrorKind::UndefinedLabel(String::from("middle"))));
+    }
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        assert_eq!(value("1 / 0"), Err(ErrorKind::DivisionByZero));
+        assert_eq!(value("1 % (end - end)"), Err(ErrorKind::DivisionByZero));
+    }
+
+    #[test]
+    fn printing_parses_back_to_the_same_expression() {
+        for text in ["1 + 2 * 3", "(1 + 2) * 3", "10 - (4 - 3)", "-(end - start)", "~start & 0xff"].iter() {
+            let expr = parse(text);
+            assert_eq!(parse(&expr.to_string()), expr, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::assembly::{Image, ImageError, LinkError, Object, RelocationTarget};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Where the linker puts sections. Sections are placed in the order listed,
+// one after the other from `base` unless they have an address of their
+// own. Sections that aren't listed follow in the order they first appear
+// in the objects. Sections with the same name from different objects are
+// concatenated in object order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkScript {
+    pub base: u16,
+    pub sections: Vec<SectionPlacement>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectionPlacement {
+    pub name: String,
+    pub address: Option<u16>
+}
+
+// Linker scripts have one entry per line, numbers are decimal or 0x hex:
+//
+// base 0x0100
+// section text
+// section data 0x8000
+//
+// Lines starting with `;` are comments.
+impl LinkScript {
+    pub fn parse(text: &str) -> io::Result<LinkScript> {
+        let mut script = LinkScript::default();
+        for (number, line) in text.lines().enumerate() {
+            let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, what));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let address = |field: &str| {
+                let parsed = match field.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16),
+                    None => field.parse()
+                };
+                parsed.map_err(|_| invalid(&format!("`{}` isn't an address", field)))
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["base", base] => script.base = address(base)?,
+                ["section", name] => script.sections.push(SectionPlacement { name: String::from(*name), address: None }),
+                ["section", name, at] => script.sections.push(SectionPlacement {
+                    name: String::from(*name),
+                    address: Some(address(at)?)
+                }),
+                [kind, ..] => return Err(invalid(&format!("malformed `{}` entry", kind))),
+                [] => {}
+            }
+        }
+        Ok(script)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<LinkScript> {
+        LinkScript::parse(&fs::read_to_string(path)?)
+    }
+}
+
+// Places the sections of every object as `script` says, resolves imports
+// against the exports of the other objects and applies relocations. The
+// image starts at address 0 like the one generate_code makes.
+pub fn link(objects: &[Object], script: &LinkScript) -> Result<Vec<u16>, Vec<LinkError>> {
+    let mut errors = vec![];
+
+    let mut order: Vec<&str> = script.sections.iter().map(|placement| placement.name.as_str()).collect();
+    for object in objects {
+        for section in &object.sections {
+            if !order.contains(&section.name.as_str()) {
+                order.push(&section.name);
+            }
+        }
+    }
+    // Address of every (object, section), in placement order
+    let mut bases: HashMap<(usize, &str), u16> = HashMap::new();
+    let mut chunks = vec![];
+    let mut address = script.base as usize;
+    for name in order {
+        let placement = script.sections.iter().find(|placement| placement.name == name);
+        if let Some(at) = placement.and_then(|placement| placement.address) {
+            address = at as usize;
+        }
+        for (index, object) in objects.iter().enumerate() {
+            for section in object.sections.iter().filter(|section| section.name == name) {
+                if address + section.size > 0x10000 {
+                    errors.push(LinkError::OutOfMemory { section: section.name.clone(), object: object.name.clone() });
+                    continue;
+                }
+                bases.insert((index, name), address as u16);
+                chunks.push((index, section, address as u16));
+                address += section.size;
+            }
+        }
+    }
+
+    let mut symbols: HashMap<&str, (u16, usize)> = HashMap::new();
+    for (index, object) in objects.iter().enumerate() {
+        for export in &object.exports {
+            let base = match &export.section {
+                Some(section) => match bases.get(&(index, section.as_str())) {
+                    Some(base) => *base,
+                    None => {
+                        errors.push(LinkError::UndefinedSymbol { symbol: section.clone(), object: object.name.clone() });
+                        continue;
+                    }
+                },
+                None => 0
+            };
+            let value = base.wrapping_add(export.value);
+            if let Some((_, first)) = symbols.insert(&export.name, (value, index)) {
+                errors.push(LinkError::DuplicateSymbol {
+                    symbol: export.name.clone(),
+                    first: objects[first].name.clone(),
+                    second: object.name.clone()
+                });
+            }
+        }
+    }
+
+    let describe = |chunk: usize| {
+        let (index, section, _) = chunks[chunk];
+        format!("section `{}` of {}", section.name, objects[index].name)
+    };
+    let mut image = Image::new();
+    for (chunk, (index, section, base)) in chunks.iter().enumerate() {
+        let object = &objects[*index];
+        let mut words = section.words.clone();
+        for relocation in &section.relocations {
+            let target = match &relocation.target {
+                RelocationTarget::Section(name) => bases.get(&(*index, name.as_str())).copied().ok_or(name),
+                RelocationTarget::Import(name) => symbols.get(name.as_str()).map(|(value, _)| *value).ok_or(name)
+            };
+            match (target, words.get_mut(relocation.offset as usize)) {
+                (Ok(target), Some(word)) => *word = word.wrapping_add(target),
+                (Err(symbol), _) => errors.push(LinkError::UndefinedSymbol { symbol: symbol.clone(), object: object.name.clone() }),
+                (Ok(_), None) => errors.push(LinkError::BadRelocation {
+                    offset: relocation.offset,
+                    section: section.name.clone(),
+                    object: object.name.clone()
+                })
+            }
+        }
+        match image.write(chunk, *base, section.size, &words) {
+            Ok(()) => {},
+            Err(ImageError::Overlap(address, owner)) => {
+                errors.push(LinkError::Overlap { address, first: describe(owner), second: describe(chunk) });
+            },
+            Err(ImageError::Overflow) => errors.push(LinkError::OutOfMemory { section: section.name.clone(), object: object.name.clone() })
+        }
+    }
+    if errors.is_empty() {
+        Ok(image.words)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly::{self, assemble_object, ErrorKind, Options};
+
+    fn object(name: &str, source: &str) -> Object {
+        assemble_object(name, &assembly::parse(source).unwrap(), &Options::default()).unwrap()
+    }
+
+    #[test]
+    fn sections_ending_in_reserve_keep_their_space() {
+        let a = object("a.s", "
+            .section bss
+            buf: .reserve 4
+        ");
+        let b = object("b.s", "
+            .section bss
+            buf2: .reserve 2
+            .section data
+            d: dat buf2
+        ");
+        assert_eq!(a.sections[0].size, 4);
+        assert!(a.sections[0].words.is_empty());
+
+        let image = link(&[a, b], &LinkScript::default()).unwrap();
+        // bss of a at 0, bss of b at 4, data after both
+        assert_eq!(image, [0, 0, 0, 0, 0, 0, 4]);
+    }
+
+    #[test]
+    fn sections_ending_in_align_keep_their_space() {
+        let a = object("a.s", "
+            dat 1
+            .align 4
+        ");
+        let b = object("b.s", "dat 2");
+        let image = link(&[a, b], &LinkScript::default()).unwrap();
+        assert_eq!(image, [1, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn reserved_space_counts_for_overlaps() {
+        let a = object("a.s", "
+            .section bss
+            .reserve 4
+            .section data
+            dat 1
+        ");
+        let script = LinkScript::parse("section bss 0\nsection data 2").unwrap();
+        let errors = link(&[a], &script).unwrap_err();
+        assert!(matches!(errors[0], LinkError::Overlap { address: 2, .. }));
+    }
+
+    #[test]
+    fn object_files_keep_the_size() {
+        let a = object("a.s", "
+            dat 1, 2
+            .reserve 3
+        ");
+        let text = a.to_string();
+        assert!(text.contains("size 0005"));
+        assert_eq!(Object::parse(&text).unwrap(), a);
+    }
+
+    #[test]
+    fn only_symbol_plus_constant_is_relocated() {
+        let a = object("a.s", "
+            .define offset here + 2
+            start: dat 0
+            here: dat here + 3, 3 + here - 1, -(-here), offset
+            dat end - start, (end - start) * 2, end - here + start
+            end:
+        ");
+        let script = LinkScript::parse("section text 0x100").unwrap();
+        assert_eq!(link(&[a], &script).unwrap()[0x100..], [
+            0, 0x104, 0x103, 0x101, 0x103,
+            8, 16, 0x107
+        ]);
+    }
+
+    #[test]
+    fn masked_and_modulo_expressions_are_not_relocatable() {
+        // A mask wider than the section hides a shift, linked at 0x100
+        // this would be 3 rather than 0x103
+        let a = assemble_object("a.s", &assembly::parse("dat 0, 0, 0\nhere: dat here & 0xfff").unwrap(), &Options::default());
+        let errors = a.unwrap_err();
+        assert_eq!(errors[0].kind, ErrorKind::NotRelocatable(String::from("here & 4095")));
+        assert_eq!(errors[0].span.column, 11);
+        for expr in ["here & 0xfff", "here % 0x1000", "here * 2", "-here", "here + here", "here >> 1", "mask"] {
+            let source = format!(".define mask here | 1\nhere: dat {}", expr);
+            let errors = assemble_object("a.s", &assembly::parse(&source).unwrap(), &Options::default()).unwrap_err();
+            assert!(matches!(&errors[0].kind, ErrorKind::NotRelocatable(_)), "{}", expr);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use crate::assembly::{layout, AssembleError, Expr, Layout, Located, Options, Span};
+use crate::dcpu::Command;
+
+use std::fmt::Write;
+
+// Words shown on one row, longer data continues on the rows below
+const WORDS_PER_ROW: usize = 4;
+
+// One source line and everything it assembled to
+struct Row<'a> {
+    address: u16,
+    words: Vec<u16>,
+    cycles: Option<String>,
+    span: &'a Span,
+    depth: usize // macro expansions are indented
+}
+
+// Listing of the program: every source line that produced a statement with
+// its address, encoded words and cycle cost. Lines expanded from a macro
+// follow the line that called it, indented.
+//
+// addr  words                cyc  line  source
+// 0000  7c21 03e9              2     3  set b, array + 1
+pub fn listing(program: &[Located], options: &Options) -> Result<String, Vec<AssembleError>> {
+    let Layout { labels, addresses, short } = layout(program, options)?;
+    let eval = |expr: &Expr| expr.eval(&labels);
+    let mut rows: Vec<Row> = vec![];
+    let mut errors = vec![];
+    // Macro calls the previous row was expanded from, outermost first
+    let mut open_calls: Vec<&Span> = vec![];
+    for ((located, short), address) in program.iter().zip(short).zip(addresses) {
+        let words = match located.statement.encode(&eval, short) {
+            Ok(words) => words,
+            Err(kind) => {
+                errors.push(located.error(kind, &eval));
+                continue;
+            }
+        };
+        let cycles = match located.statement.to_command(&eval, short) {
+            // A failed test costs one more cycle
+            Ok(Some(command)) => Some(match command {
+                Command::Basic { op, .. } if op.is_conditional() => format!("{}+", command.cycles()),
+                _ => command.cycles().to_string()
+            }),
+            _ => None
+        };
+
+        let calls = expansion(&located.span);
+        let common = open_calls.iter().zip(&calls).take_while(|(open, call)| open == call).count();
+        for (depth, call) in calls.iter().enumerate().skip(common) {
+            // Labels before the call are a line of their own after
+            // preprocessing, the call takes over their row
+            if let Some(row) = rows.last_mut() {
+                if same_line(row.span, call) && row.address == address && row.words.is_empty() {
+                    row.span = call;
+                    continue;
+                }
+            }
+            rows.push(Row { address, words: vec![], cycles: None, span: call, depth });
+        }
+        open_calls = calls;
+
+        // Statements on the same line, like a label and an instruction,
+        // share a row
+        if let Some(row) = rows.last_mut() {
+            if same_line(row.span, &located.span) && row.address.wrapping_add(row.words.len() as u16) == address {
+                row.words.extend(words);
+                row.cycles = match (row.cycles.take(), cycles) {
+                    (Some(total), Some(cycles)) => Some(format!("{}+{}", total, cycles)),
+                    (total, cycles) => total.or(cycles)
+                };
+                continue;
+            }
+        }
+        rows.push(Row {
+            address,
+            words,
+            cycles,
+            span: &located.span,
+            depth: open_calls.len()
+        });
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut text = String::new();
+    let mut file = None;
+    writeln!(text, "addr  words                cyc  line  source").unwrap();
+    for row in rows {
+        if file != Some(&row.span.file) {
+            writeln!(text, "; {}", row.span.file).unwrap();
+            file = Some(&row.span.file);
+        }
+        let mut chunks = row.words.chunks(WORDS_PER_ROW);
+        let first = chunks.next().unwrap_or(&[]);
+        writeln!(text, "{:04x}  {:<19}  {:>3}  {:>4}  {}{}",
+                 row.address,
+                 hex_words(first),
+                 row.cycles.unwrap_or_default(),
+                 row.span.line,
+                 "    ".repeat(row.depth),
+                 row.span.snippet.trim()).unwrap();
+        let mut address = row.address.wrapping_add(first.len() as u16);
+        for chunk in chunks {
+            writeln!(text, "{:04x}  {}", address, hex_words(chunk)).unwrap();
+            address = address.wrapping_add(chunk.len() as u16);
+        }
+    }
+    Ok(text)
+}
+
+// Calls a span was expanded from, outermost first
+fn expansion(span: &Span) -> Vec<&Span> {
+    let mut calls = vec![];
+    let mut call = &span.expanded_from;
+    while let Some(span) = call {
+        calls.push(&**span);
+        call = &span.expanded_from;
+    }
+    calls.reverse();
+    calls
+}
+
+fn same_line(a: &Span, b: &Span) -> bool {
+    a.file == b.file && a.line == b.line && a.expanded_from == b.expanded_from
+}
+
+fn hex_words(words: &[u16]) -> String {
+    words.iter().map(|word| format!("{:04x}", word)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembly::{listing, parse_source, Options};
+
+    #[test]
+    fn macro_calls_are_listed_once_with_their_expansion() {
+        let source = "\
+.macro two(n)
+set a, n
+set b, n
+.endmacro
+start: two(5)
+two(6)
+set pc, start
+";
+        let program = parse_source("t.s", source).unwrap();
+        assert_eq!(listing(&program, &Options::default()).unwrap(), "\
+addr  words                cyc  line  source
+; t.s
+0000                               5  start: two(5)
+0000  9801                   1     2      set a, 5
+0001  9821                   1     3      set b, 5
+0002                               6  two(6)
+0002  9c01                   1     2      set a, 6
+0003  9c21                   1     3      set b, 6
+0004  8781                   1     7  set pc, start
+");
+    }
+}
@@ -1,35 +1,440 @@
 extern crate nom;
 mod parser;
+mod preprocessor;
+mod scope;
+mod error;
+mod expr;
+mod operand;
+mod span;
+mod statement;
+mod listing;
+mod symbols;
+mod object;
+mod link;
+
+pub use error::*;
+pub use expr::*;
+pub use operand::*;
+pub use span::*;
+pub use statement::*;
+pub use listing::*;
+pub use symbols::*;
+pub use object::*;
+pub use link::*;
 
-use crate::dcpu;
 use parser::*;
 
-pub fn parse(s: &str) -> Option<Vec<dcpu::Command>> {
-    match parse_program(s) {
-        Ok((_, program)) => Some(program),
-        _ => None
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn parse(s: &str) -> Result<Vec<Located>, Vec<AssembleError>> {
+    parse_source("<input>", s)
+}
+
+// Like parse, errors are reported against `file`
+pub fn parse_source(file: &str, source: &str) -> Result<Vec<Located>, Vec<AssembleError>> {
+    parse_source_with(file, source, &Options::default())
+}
+
+pub fn parse_source_with(file: &str, source: &str, options: &Options) -> Result<Vec<Located>, Vec<AssembleError>> {
+    parse_program(file, source, options)
+}
+
+// Parses and assembles `source` in one go. The statements that parsed are
+// still laid out and encoded when other lines don't, so undefined labels
+// and the like are reported together with syntax errors, in source order.
+pub fn assemble_source(file: &str, source: &str, options: &Options) -> Result<Vec<u16>, Vec<AssembleError>> {
+    let (program, mut errors) = parse_statements(file, source, options);
+    match generate_code_with(program, options) {
+        Ok(code) if errors.is_empty() => return Ok(code),
+        Ok(_) => {},
+        Err(more) => errors.extend(more)
     }
+    errors.sort_by(|a, b| (&a.span.file, a.span.line, a.span.column).cmp(&(&b.span.file, b.span.line, b.span.column)));
+    Err(errors)
 }
 
-pub fn generate_code(program: Vec<dcpu::Command>) -> Vec<u16> {
-    let mut result = vec![];
-    for command in program {
-        result.push(command.code());
-        match command {
-            dcpu::Command::Special { op: _, a } => {
-                if let Some(word) = dcpu::get_next_word(&a) {
-                    result.push(word);
-                };
-            },
-            dcpu::Command::Basic { op: _, b, a } => {
-                if let Some(word) = dcpu::get_next_word(&a) {
-                    result.push(word);
-                };
-                if let Some(word) = dcpu::get_next_word(&b) {
-                    result.push(word);
-                };
+// Reads and parses the file at `path`, includes are resolved relative to it
+pub fn parse_file(path: &Path, options: &Options) -> Result<Vec<Located>, Vec<AssembleError>> {
+    let file = path.display().to_string();
+    match fs::read_to_string(path) {
+        Ok(source) => parse_source_with(&file, &source, options),
+        Err(error) => {
+            let kind = ErrorKind::CantRead { path: file.clone(), message: error.to_string() };
+            Err(vec![AssembleError::new(Span::new(&file, "", 0), kind)])
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Encode literals in a inline when they fit. Turning this off keeps
+    // instruction sizes independent of label values, so a binary can be
+    // patched without moving code.
+    pub short_literals: bool,
+    // Statements end with `;` instead of a newline, and there are no
+    // comments. This is the syntax older versions of the assembler used.
+    pub legacy_syntax: bool,
+    // Directories searched by .include and .incbin after the directory of
+    // the including file
+    pub include_paths: Vec<PathBuf>
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            short_literals: true,
+            legacy_syntax: false,
+            include_paths: vec![]
+        }
+    }
+}
+
+// Label addresses and, for every statement, its address and whether its
+// a operand is encoded as a short literal
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub labels: HashMap<String, u16>,
+    pub addresses: Vec<u16>,
+    pub short: Vec<bool>
+}
+
+// After this many passes operands may only grow, which guarantees that
+// the layout converges even for expressions like `30 - label`.
+const MAX_SHRINKING_PASSES: usize = 16;
+
+// .org and friends can depend on labels, which depend on them in turn.
+// A layout that hasn't settled by then is an error.
+const MAX_PASSES: usize = 64;
+
+struct Placement {
+    labels: HashMap<String, u16>,
+    addresses: Vec<u16>,
+    errors: Vec<AssembleError>
+}
+
+// Assigns addresses, layout directives are evaluated with the symbols
+// from the previous pass
+fn place(program: &[Located], short: &[bool], known: &HashMap<String, u16>) -> Placement {
+    let mut labels = HashMap::new();
+    let mut addresses = vec![];
+    let mut errors = vec![];
+    // Up to 0x10000 when the statements so far fill memory to the end
+    let mut address: usize = 0;
+    // Running past the end is reported once per .org
+    let mut overflowed = false;
+    let eval = |expr: &Expr| expr.eval(known);
+    for (located, short) in program.iter().zip(short) {
+        if let Statement::Org(expr) = &located.statement {
+            match eval(expr) {
+                Ok(origin) => {
+                    address = origin as usize;
+                    overflowed = false;
+                },
+                Err(kind) => errors.push(located.error(kind, &eval))
             }
         }
+        addresses.push(address as u16);
+        // Imports are placeholders at 0 until the object is linked
+        let defined = match &located.statement {
+            Statement::Label(label) => vec![(label, address as u16)],
+            Statement::Extern(names) => names.iter().map(|name| (name, 0)).collect(),
+            _ => vec![]
+        };
+        for (label, address) in defined {
+            if labels.insert(label.clone(), address).is_some() {
+                errors.push(AssembleError::new(located.span.clone(), ErrorKind::DuplicateLabel(label.clone())));
+            }
+        }
+        let next = match located.statement.next_address(address, *short, &eval) {
+            Ok(next) => next,
+            Err(kind) => {
+                errors.push(located.error(kind, &eval));
+                address
+            }
+        };
+        // A label right after the last word has no address either
+        let label_past_end = address > 0xffff && matches!(located.statement, Statement::Label(_));
+        if (next > 0x10000 || label_past_end) && !overflowed {
+            errors.push(AssembleError::new(located.span.clone(), ErrorKind::AddressOverflow));
+            overflowed = true;
+        }
+        address = next.min(0x10000);
+    }
+    // Defines can use any label and the defines before them
+    for located in program {
+        if let Statement::Define(name, expr) = &located.statement {
+            match expr.eval(&labels) {
+                Ok(value) => if labels.insert(name.clone(), value).is_some() {
+                    errors.push(AssembleError::new(located.span.clone(), ErrorKind::DuplicateLabel(name.clone())));
+                },
+                Err(kind) => errors.push(located.error(kind, &|expr: &Expr| expr.eval(&labels)))
+            }
+        }
+    }
+    Placement { labels, addresses, errors }
+}
+
+// Short literals change instruction sizes, which move labels, which can
+// change which literals fit, so the layout is repeated until it settles.
+pub fn layout(program: &[Located], options: &Options) -> Result<Layout, Vec<AssembleError>> {
+    layout_with(program, options, &|_| true)
+}
+
+// Layout where only statements `can_shorten` accepts may use short literals
+fn layout_with(program: &[Located], options: &Options, can_shorten: &dyn Fn(&Located) -> bool)
+    -> Result<Layout, Vec<AssembleError>> {
+    let mut short = vec![false; program.len()];
+    let mut placement = place(program, &short, &HashMap::new());
+    // Statement that still moved or changed size in the last pass
+    let mut moving = None;
+    for pass in 0..MAX_PASSES {
+        let next_short: Vec<bool> = program.iter().zip(&short)
+            .map(|(located, was_short)| {
+                let fits = options.short_literals && can_shorten(located) && located.statement.short_literal(&placement.labels);
+                if pass < MAX_SHRINKING_PASSES { fits } else { fits && *was_short }
+            })
+            .collect();
+        let next = place(program, &next_short, &placement.labels);
+        moving = if next_short == short && next.labels == placement.labels {
+            None
+        } else {
+            // The first statement that moved did so because of its own .org
+            // or the size of the one before it. Defines can change without
+            // anything moving.
+            Some((0..program.len())
+                .find_map(|i| if next_short[i] != short[i] {
+                    Some(i)
+                } else if next.addresses[i] != placement.addresses[i] {
+                    Some(if i == 0 || matches!(program[i].statement, Statement::Org(_)) { i } else { i - 1 })
+                } else {
+                    None
+                })
+                .unwrap_or(0))
+        };
+        short = next_short;
+        placement = next;
+        if moving.is_none() {
+            break;
+        }
+    }
+    if let Some(index) = moving {
+        return Err(vec![AssembleError::new(program[index].span.clone(), ErrorKind::LayoutDidNotConverge)]);
+    }
+    if !placement.errors.is_empty() {
+        return Err(placement.errors);
+    }
+    Ok(Layout { labels: placement.labels, addresses: placement.addresses, short })
+}
+
+pub fn generate_code(program: Vec<Located>) -> Result<Vec<u16>, Vec<AssembleError>> {
+    generate_code_with(program, &Options::default())
+}
+
+// Memory image being built, remembers what occupies each address so
+// overlapping sections can be reported
+struct Image {
+    words: Vec<u16>,
+    owners: Vec<Option<usize>>
+}
+
+impl Image {
+    fn new() -> Image {
+        Image { words: vec![], owners: vec![None; 0x10000] }
+    }
+
+    // Claims `size` addresses from `address` for `owner` and writes `words`
+    // at the start. On overlap the words are written anyway, nothing is
+    // written when they don't fit in memory.
+    fn write(&mut self, owner: usize, address: u16, size: usize, words: &[u16]) -> Result<(), ImageError> {
+        let end = address as usize + size.max(words.len());
+        if end > self.owners.len() {
+            return Err(ImageError::Overflow);
+        }
+        let mut overlap = None;
+        for target in address as usize..address as usize + size {
+            match self.owners[target] {
+                Some(other) if overlap.is_none() => overlap = Some(ImageError::Overlap(target as u16, other)),
+                Some(_) => {},
+                None => self.owners[target] = Some(owner)
+            }
+        }
+        let start = address as usize;
+        if self.words.len() < start + words.len() {
+            self.words.resize(start + words.len(), 0);
+        }
+        self.words[start..start + words.len()].copy_from_slice(words);
+        overlap.map_or(Ok(()), Err)
+    }
+}
+
+enum ImageError {
+    // Address already used and its previous owner
+    Overlap(u16, usize),
+    // Past 0xffff
+    Overflow
+}
+
+// Memory image starting at address 0, gaps between sections are zero.
+// Reports every statement that fails, not just the first one.
+pub fn generate_code_with(program: Vec<Located>, options: &Options) -> Result<Vec<u16>, Vec<AssembleError>> {
+    let Layout { labels, addresses, short } = layout(&program, options)?;
+    let eval = |expr: &Expr| expr.eval(&labels);
+    let mut image = Image::new();
+    let mut errors = vec![];
+    for (i, ((located, short), address)) in program.iter().zip(short).zip(addresses).enumerate() {
+        let error = |kind| AssembleError::new(located.span.clone(), kind);
+        if let Statement::Extern(names) = &located.statement {
+            errors.extend(names.iter().map(|name| error(ErrorKind::UnlinkedImport(name.clone()))));
+        }
+        let words = match located.statement.encode(&eval, short) {
+            Ok(words) => words,
+            Err(kind) => {
+                errors.push(located.error(kind, &eval));
+                continue;
+            }
+        };
+        let reserved = match &located.statement {
+            Statement::Reserve(count) => eval(count).unwrap_or(0),
+            _ => 0
+        };
+        let size = words.len().max(reserved as usize);
+        match image.write(i, address, size, &words) {
+            Ok(()) => {},
+            Err(ImageError::Overlap(address, owner)) => {
+                let other = program[owner].span.to_string();
+                errors.push(error(ErrorKind::Overlap { address, other }));
+            },
+            Err(ImageError::Overflow) => errors.push(error(ErrorKind::AddressOverflow))
+        }
+    }
+    if errors.is_empty() {
+        Ok(image.words)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_semantic_errors_are_reported_together() {
+        let source = "set a, undefined\nset a, 1/0\nset a, 1 2\n";
+        let errors = assemble_source("t.s", source, &Options::default()).unwrap_err();
+        let lines: Vec<(usize, &ErrorKind)> = errors.iter().map(|error| (error.span.line, &error.kind)).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], (1, &ErrorKind::UndefinedLabel(String::from("undefined"))));
+        assert_eq!(lines[1], (2, &ErrorKind::DivisionByZero));
+        assert_eq!(lines[2].0, 3);
+    }
+
+    #[test]
+    fn programs_past_the_end_of_memory_are_rejected() {
+        let errors = assemble_source("t.s", ".org 0xfffe\ndat 1, 2, 3\nset a, 1\n", &Options::default()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ErrorKind::AddressOverflow);
+        assert_eq!(errors[0].span.line, 2);
+        assert!(errors[0].to_string().contains("exceeds 64K words"));
+
+        let errors = assemble_source("t.s", ".org 0xffff\nset a, 1\nend:\n", &Options::default()).unwrap_err();
+        assert_eq!(errors[0].span.line, 3);
+
+        let code = assemble_source("t.s", ".org 0xffff\ndat 7\n", &Options::default()).unwrap();
+        assert_eq!(code.len(), 0x10000);
+        assert_eq!(code[0xffff], 7);
+    }
+
+    fn assemble(source: &str) -> Vec<u16> {
+        assemble_source("t.s", source, &Options::default()).unwrap()
+    }
+
+    fn errors(source: &str) -> Vec<ErrorKind> {
+        assemble_source("t.s", source, &Options::default()).unwrap_err().into_iter().map(|error| error.kind).collect()
+    }
+
+    #[test]
+    fn labels_resolve_forwards_and_backwards() {
+        let labelled = assemble("
+                set pc, end
+            loop:
+                add a, 1
+                ifl a, 3
+                set pc, loop
+            end:
+                dat end, loop
+        ");
+        let numbered = assemble("
+                set pc, 4
+                add a, 1
+                ifl a, 3
+                set pc, 1
+                dat 4, 1
+        ");
+        assert_eq!(labelled, numbered);
+    }
+
+    #[test]
+    fn labels_work_in_every_operand_form() {
+        let labelled = assemble("
+                set [table], [table]
+                set a, [b + table]
+                set [table + b], table
+                set x, [table + 2]
+            .org 0x100
+            table:
+        ");
+        let numbered = assemble("
+                set [0x100], [0x100]
+                set a, [b + 0x100]
+                set [0x100 + b], 0x100
+                set x, [0x102]
+        ");
+        assert_eq!(labelled[..numbered.len()], numbered[..]);
+    }
+
+    #[test]
+    fn forward_references_can_shrink_code() {
+        // `end` fits in a short literal once the jump is known to be 1 word
+        let code = assemble("
+                set pc, end
+            end:
+                dat 0
+        ");
+        assert_eq!(code.len(), 2);
+        let layout = layout(&parse("set pc, end\nend:").unwrap(), &Options::default()).unwrap();
+        assert_eq!(layout.labels["end"], 1);
+    }
+
+    #[test]
+    fn undefined_and_duplicate_labels_are_errors() {
+        assert_eq!(errors("set pc, nowhere"), [ErrorKind::UndefinedLabel(String::from("nowhere"))]);
+        assert_eq!(errors("here:\nhere:"), [ErrorKind::DuplicateLabel(String::from("here"))]);
+    }
+
+    #[test]
+    fn layouts_that_never_settle_are_errors() {
+        // `end` is 1 when the fill is empty and 0 when it isn't
+        let errors = assemble_source("t.s", "set a, 1\n.fill 1 - end + 1, 7\nend:\n", &Options::default()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ErrorKind::LayoutDidNotConverge);
+        assert_eq!(errors[0].span.line, 2);
+    }
+
+    #[test]
+    fn layout_directives_can_use_later_labels() {
+        // Every .org moves `end` past itself
+        let errors = assemble_source("t.s", ".org end\ndat 0\nend:\n", &Options::default()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].span.line, &errors[0].kind), (1, &ErrorKind::LayoutDidNotConverge));
+        let errors = assemble_source("t.s", "dat 1\n.align end\ndat 2\nend:\n", &Options::default()).unwrap_err();
+        assert_eq!((errors[0].span.line, &errors[0].kind), (2, &ErrorKind::LayoutDidNotConverge));
+        // These settle
+        assert_eq!(assemble(".org end - 3\ndat 1, 2, 3\nend:"), [1, 2, 3]);
+        let mut code = vec![0; 0x20];
+        code.push(1);
+        assert_eq!(assemble(".org target\ndat 1\n.org 0x20\ntarget:"), code);
     }
-    result
 }
@@ -0,0 +1,384 @@
+use crate::assembly::{layout_with, AssembleError, BinaryOp, ErrorKind, Expr, Image, ImageError, Layout, Located, Operand, Options, Statement, UnaryOp};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Code before the first .section goes here
+pub const DEFAULT_SECTION: &str = "text";
+
+// Words per `words` entry in object files
+const WORDS_PER_ENTRY: usize = 8;
+
+// Relocatable object: sections assembled as if they started at 0, with
+// relocations for the words that depend on where they end up
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Object {
+    pub name: String,
+    pub sections: Vec<Section>,
+    pub exports: Vec<Export>,
+    pub imports: Vec<String>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub words: Vec<u16>,
+    // Addresses the section takes, more than `words` when it ends with
+    // .reserve or .align
+    pub size: usize,
+    pub relocations: Vec<Relocation>
+}
+
+// The address of `target` is added to the word at `offset` in the section
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: u16,
+    pub target: RelocationTarget
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelocationTarget {
+    // A section of the same object
+    Section(String),
+    Import(String)
+}
+
+// Symbol other objects can import, `value` is relative to `section` or
+// absolute without one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub name: String,
+    pub value: u16,
+    pub section: Option<String>
+}
+
+// Assembles the program into an object named `name`. Every section starts
+// at 0, .org and .align are relative to the start of the section. Operands
+// that use symbols are never short literals since their value is only
+// known after linking.
+pub fn assemble_object(name: &str, program: &[Located], options: &Options) -> Result<Object, Vec<AssembleError>> {
+    // Statements grouped by section in order of first appearance, each
+    // group starts at 0
+    let mut names = vec![String::from(DEFAULT_SECTION)];
+    let mut groups: Vec<Vec<Located>> = vec![vec![]];
+    let mut current = 0;
+    for located in program {
+        if let Statement::Section(section) = &located.statement {
+            current = match names.iter().position(|name| name == section) {
+                Some(index) => index,
+                None => {
+                    names.push(section.clone());
+                    groups.push(vec![]);
+                    names.len() - 1
+                }
+            };
+        }
+        groups[current].push(located.clone());
+    }
+    let used: Vec<bool> = groups.iter().map(|group| !group.is_empty()).collect();
+    let mut ordered = vec![];
+    let mut section_of = vec![];
+    for (index, group) in groups.into_iter().enumerate() {
+        if let Some(first) = group.first() {
+            ordered.push(Located { statement: Statement::Org(Expr::Number(0)), span: first.span.clone(), operands: vec![] });
+            section_of.push(index);
+        }
+        section_of.extend(vec![index; group.len()]);
+        ordered.extend(group);
+    }
+
+    let can_shorten = |located: &Located| match &located.statement {
+        Statement::Basic { a: Operand::NextWord(expr), .. } | Statement::Special { a: Operand::NextWord(expr), .. } => expr.is_constant(),
+        _ => true
+    };
+    let Layout { labels, addresses, short } = layout_with(&ordered, options, &can_shorten)?;
+    let eval = |expr: &Expr| expr.eval(&labels);
+
+    // Sections come first, then imports
+    let imports: Vec<String> = ordered.iter()
+        .flat_map(|located| match &located.statement {
+            Statement::Extern(names) => names.clone(),
+            _ => vec![]
+        })
+        .collect();
+    let mut bases: HashMap<&str, usize> = HashMap::new();
+    for (located, section) in ordered.iter().zip(&section_of) {
+        if let Statement::Label(label) = &located.statement {
+            bases.insert(label, *section);
+        }
+    }
+    for (index, import) in imports.iter().enumerate() {
+        bases.insert(import, names.len() + index);
+    }
+    let defines: HashMap<&str, &Expr> = ordered.iter()
+        .filter_map(|located| match &located.statement {
+            Statement::Define(name, expr) => Some((name.as_str(), expr)),
+            _ => None
+        })
+        .collect();
+    let dependence = Dependence { bases: &bases, defines: &defines, count: names.len() + imports.len() };
+    // Value at base 0 and the section or import the expression moves with.
+    // Only `symbol + constant` moves with its symbol, anything else that
+    // depends on where sections end up can't be relocated.
+    let relocate = |expr: &Expr| -> Result<(u16, Option<usize>), ErrorKind> {
+        let value = eval(expr)?;
+        let not_relocatable = || ErrorKind::NotRelocatable(expr.to_string());
+        let mut target = None;
+        for (base, times) in dependence.of(expr).ok_or_else(not_relocatable)?.into_iter().enumerate() {
+            match times {
+                0 => {},
+                1 if target.is_none() => target = Some(base),
+                _ => return Err(not_relocatable())
+            }
+        }
+        Ok((value, target))
+    };
+    let target_of = |base: usize| match base.checked_sub(names.len()) {
+        Some(import) => RelocationTarget::Import(imports[import].clone()),
+        None => RelocationTarget::Section(names[base].clone())
+    };
+
+    let mut images: Vec<Image> = names.iter().map(|_| Image::new()).collect();
+    let mut sizes = vec![0; names.len()];
+    let mut relocations: Vec<Vec<Relocation>> = vec![vec![]; names.len()];
+    let mut exports = vec![];
+    let mut errors = vec![];
+    for (i, ((located, short), address)) in ordered.iter().zip(short).zip(addresses).enumerate() {
+        let error = |kind| AssembleError::new(located.span.clone(), kind);
+        let section = section_of[i];
+        if let Ok(end) = located.statement.next_address(address as usize, short, &eval) {
+            sizes[section] = sizes[section].max(end);
+        }
+        if let Statement::Global(globals) = &located.statement {
+            for global in globals {
+                match relocate(&Expr::Label(global.clone())) {
+                    Ok((value, None)) => exports.push(Export { name: global.clone(), value, section: None }),
+                    Ok((value, Some(base))) if base < names.len() => {
+                        exports.push(Export { name: global.clone(), value, section: Some(names[base].clone()) });
+                    },
+                    Ok(_) => errors.push(error(ErrorKind::IllegalOperand(format!("`{}` is imported, it can't be exported too", global)))),
+                    Err(kind) => errors.push(error(kind))
+                }
+            }
+        }
+        let words = match located.statement.encode(&eval, short) {
+            Ok(words) => words,
+            Err(kind) => {
+                errors.push(located.error(kind, &eval));
+                continue;
+            }
+        };
+        // Expression behind every word, the instruction word itself has
+        // none since symbols are never short literals
+        let exprs: Vec<Option<&Expr>> = match &located.statement {
+            Statement::Data(exprs) => exprs.iter().map(Some).collect(),
+            Statement::Fill(_, value) => vec![Some(value); words.len()],
+            Statement::Basic { op: _, b, a } => vec![None].into_iter()
+                .chain(a.expr().filter(|_| !short).map(Some))
+                .chain(b.expr().map(Some))
+                .collect(),
+            Statement::Special { op: _, a } => vec![None].into_iter()
+                .chain(a.expr().filter(|_| !short).map(Some))
+                .collect(),
+            _ => vec![]
+        };
+        for (offset, expr) in exprs.into_iter().enumerate() {
+            let expr = match expr {
+                Some(expr) => expr,
+                None => continue
+            };
+            match relocate(expr) {
+                Ok((_, Some(base))) => relocations[section].push(Relocation {
+                    offset: address.wrapping_add(offset as u16),
+                    target: target_of(base)
+                }),
+                Ok(_) => {},
+                Err(kind) => errors.push(located.error(kind, &|expr: &Expr| relocate(expr).map(|(value, _)| value)))
+            }
+        }
+        let reserved = match &located.statement {
+            Statement::Reserve(count) => eval(count).unwrap_or(0),
+            _ => 0
+        };
+        let size = words.len().max(reserved as usize);
+        match images[section].write(i, address, size, &words) {
+            Ok(()) => {},
+            Err(ImageError::Overlap(address, owner)) => {
+                let other = ordered[owner].span.to_string();
+                errors.push(error(ErrorKind::Overlap { address, other }));
+            },
+            Err(ImageError::Overflow) => errors.push(error(ErrorKind::AddressOverflow))
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let sections = names.into_iter()
+        .zip(images)
+        .zip(relocations)
+        .zip(sizes)
+        .zip(used)
+        .filter(|(_, used)| *used)
+        .map(|((((name, image), relocations), size), _)| Section { name, words: image.words, size, relocations })
+        .collect();
+    Ok(Object { name: String::from(name), sections, exports, imports })
+}
+
+// Which sections and imports an expression moves with
+struct Dependence<'a> {
+    // Section or import of every label and import
+    bases: &'a HashMap<&'a str, usize>,
+    defines: &'a HashMap<&'a str, &'a Expr>,
+    count: usize
+}
+
+impl<'a> Dependence<'a> {
+    // How many times the address of every section and import is added to
+    // the expression, None when it doesn't move with them by sums alone,
+    // like `label & 0xfff`. Labels that cancel out, like `end - start`,
+    // are constant.
+    fn of(&self, expr: &Expr) -> Option<Vec<i32>> {
+        Some(match expr {
+            Expr::Number(_) => vec![0; self.count],
+            Expr::Label(name) => match (self.bases.get(name.as_str()), self.defines.get(name.as_str())) {
+                (Some(&base), _) => {
+                    let mut times = vec![0; self.count];
+                    times[base] = 1;
+                    times
+                },
+                (None, Some(define)) => self.of(define)?,
+                (None, None) => vec![0; self.count]
+            },
+            Expr::Unary(UnaryOp::Neg, inner) => self.of(inner)?.into_iter().map(|times| -times).collect(),
+            Expr::Binary(op, lhs, rhs) if *op == BinaryOp::Add || *op == BinaryOp::Sub => {
+                let sign = if *op == BinaryOp::Add { 1 } else { -1 };
+                self.of(lhs)?.into_iter().zip(self.of(rhs)?).map(|(lhs, rhs)| lhs + sign * rhs).collect()
+            },
+            // Anything else only works on constants
+            Expr::Unary(_, inner) => self.constant(inner)?,
+            Expr::Binary(_, lhs, rhs) => {
+                self.constant(lhs)?;
+                self.constant(rhs)?
+            }
+        })
+    }
+
+    fn constant(&self, expr: &Expr) -> Option<Vec<i32>> {
+        self.of(expr).filter(|times| times.iter().all(|&times| times == 0))
+    }
+}
+
+// Object files are text, one entry per line. `size`, `words` and `reloc`
+// belong to the section before them, numbers are hex.
+//
+// object main.s
+// section text
+// size 0004
+// words 7c01 0000 7f81 0002
+// reloc 0001 section data
+// reloc 0003 import putc
+// export main 0000 text
+// import putc
+//
+// Lines starting with `;` are comments.
+impl Object {
+    pub fn parse(text: &str) -> io::Result<Object> {
+        let mut object = Object::default();
+        for (number, line) in text.lines().enumerate() {
+            let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, what));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let hex = |field: &str| u16::from_str_radix(field, 16).map_err(|_| invalid(&format!("`{}` isn't a hex number", field)));
+            match fields.as_slice() {
+                // Object names are paths, they can contain spaces
+                ["object", ..] => object.name = String::from(line["object".len()..].trim()),
+                ["section", name] => object.sections.push(Section {
+                    name: String::from(*name),
+                    words: vec![],
+                    size: 0,
+                    relocations: vec![]
+                }),
+                ["size", size] => {
+                    let size = usize::from_str_radix(size, 16).map_err(|_| invalid(&format!("`{}` isn't a hex number", size)))?;
+                    object.sections.last_mut()
+                        .ok_or_else(|| invalid("`size` outside a section"))?
+                        .size = size;
+                },
+                ["words", words @ ..] => {
+                    let words = words.iter().map(|word| hex(word)).collect::<io::Result<Vec<u16>>>()?;
+                    object.sections.last_mut()
+                        .ok_or_else(|| invalid("`words` outside a section"))?
+                        .words.extend(words);
+                },
+                ["reloc", offset, kind, name] => {
+                    let target = match *kind {
+                        "section" => RelocationTarget::Section(String::from(*name)),
+                        "import" => RelocationTarget::Import(String::from(*name)),
+                        kind => return Err(invalid(&format!("unknown relocation target `{}`", kind)))
+                    };
+                    let relocation = Relocation { offset: hex(offset)?, target };
+                    object.sections.last_mut()
+                        .ok_or_else(|| invalid("`reloc` outside a section"))?
+                        .relocations.push(relocation);
+                },
+                ["export", name, value, section @ ..] if section.len() <= 1 => object.exports.push(Export {
+                    name: String::from(*name),
+                    value: hex(value)?,
+                    section: section.first().map(|section| String::from(*section))
+                }),
+                ["import", name] => object.imports.push(String::from(*name)),
+                [kind, ..] => return Err(invalid(&format!("malformed `{}` entry", kind))),
+                [] => {}
+            }
+        }
+        // Sections are never smaller than their words
+        for section in &mut object.sections {
+            section.size = section.size.max(section.words.len());
+        }
+        Ok(object)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Object> {
+        Object::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "object {}", self.name)?;
+        for section in &self.sections {
+            writeln!(f, "section {}", section.name)?;
+            writeln!(f, "size {:04x}", section.size)?;
+            for words in section.words.chunks(WORDS_PER_ENTRY) {
+                let words: Vec<String> = words.iter().map(|word| format!("{:04x}", word)).collect();
+                writeln!(f, "words {}", words.join(" "))?;
+            }
+            for relocation in &section.relocations {
+                match &relocation.target {
+                    RelocationTarget::Section(name) => writeln!(f, "reloc {:04x} section {}", relocation.offset, name)?,
+                    RelocationTarget::Import(name) => writeln!(f, "reloc {:04x} import {}", relocation.offset, name)?
+                }
+            }
+        }
+        for export in &self.exports {
+            match &export.section {
+                Some(section) => writeln!(f, "export {} {:04x} {}", export.name, export.value, section)?,
+                None => writeln!(f, "export {} {:04x}", export.name, export.value)?
+            }
+        }
+        for import in &self.imports {
+            writeln!(f, "import {}", import)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use crate::assembly::{ErrorKind, Expr};
+use crate::dcpu::{Register, Value};
+
+use std::fmt;
+
+// Parsed form of dcpu::Value, next words are expressions
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Reg(Register),
+    DerefReg(Register),
+    IndexReg(Register, Expr), // [reg + n] or [n + reg]
+    PUSH, // [--SP], only in b
+    POP, // [SP++], only in a
+    PEEK, // [SP]
+    PICK(Expr), // [SP + n]
+    SP,
+    PC,
+    EX,
+    DerefNextWord(Expr),
+    NextWord(Expr)
+}
+
+impl Operand {
+    pub fn expr(&self) -> Option<&Expr> {
+        match self {
+            Operand::IndexReg(_, expr) | Operand::PICK(expr) | Operand::DerefNextWord(expr) | Operand::NextWord(expr) => Some(expr),
+            _ => None
+        }
+    }
+
+    pub fn expr_mut(&mut self) -> Option<&mut Expr> {
+        match self {
+            Operand::IndexReg(_, expr) | Operand::PICK(expr) | Operand::DerefNextWord(expr) | Operand::NextWord(expr) => Some(expr),
+            _ => None
+        }
+    }
+
+    // Converts to a Value, evaluating next words with `eval`
+    pub fn to_value<F>(&self, eval: &F) -> Result<Value, ErrorKind>
+        where F: Fn(&Expr) -> Result<u16, ErrorKind> {
+        Ok(match self {
+            Operand::Reg(reg) => Value::Reg(*reg),
+            Operand::DerefReg(reg) => Value::DerefReg(*reg),
+            Operand::IndexReg(reg, expr) => Value::IndexReg(*reg, eval(expr)?),
+            Operand::PUSH => Value::STACK,
+            Operand::POP => Value::STACK,
+            Operand::PEEK => Value::PEEK,
+            Operand::PICK(expr) => Value::PICK(eval(expr)?),
+            Operand::SP => Value::SP,
+            Operand::PC => Value::PC,
+            Operand::EX => Value::EX,
+            Operand::DerefNextWord(expr) => Value::DerefNextWord(eval(expr)?),
+            Operand::NextWord(expr) => Value::NextWord(eval(expr)?)
+        })
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "{}", reg),
+            Operand::DerefReg(reg) => write!(f, "[{}]", reg),
+            Operand::IndexReg(reg, expr) => write!(f, "[{} + {}]", reg, expr),
+            Operand::PUSH => write!(f, "push"),
+            Operand::POP => write!(f, "pop"),
+            Operand::PEEK => write!(f, "peek"),
+            Operand::PICK(expr) => write!(f, "pick {}", expr),
+            Operand::SP => write!(f, "sp"),
+            Operand::PC => write!(f, "pc"),
+            Operand::EX => write!(f, "ex"),
+            Operand::DerefNextWord(expr) => write!(f, "[{}]", expr),
+            Operand::NextWord(expr) => write!(f, "{}", expr)
+        }
+    }
+}
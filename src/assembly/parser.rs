@@ -1,152 +1,872 @@
 use crate::dcpu;
+use crate::assembly::{AssembleError, BinaryOp, ErrorKind, Expr, Located, Operand, Span, Statement, UnaryOp};
+use crate::assembly::Options;
+use crate::assembly::preprocessor::{preprocess, resolve, Line};
+use crate::assembly::scope::resolve_scopes;
 
+use std::fs;
 use std::str::FromStr;
-use nom::{tag, map_res, named, alt, ws, char, preceded, delimited, take_while, separated_pair, tuple, switch, recognize, many0, complete};
-use nom::{IResult};
-use nom::combinator::{map_res};
-use nom::branch::alt;
-use nom::character::{is_digit};
-use nom::character::complete::{multispace0};
-
-named!(parse_basic_op<&str, dcpu::BasicOp>,
-       map_res!(alt!(
-           tag!("set") |
-           tag!("add") |
-           tag!("sub") |
-           tag!("mul") |
-           tag!("mli") |
-           tag!("div") |
-           tag!("dvi") |
-           tag!("mod") |
-           tag!("mdi") |
-           tag!("and") |
-           tag!("bor") |
-           tag!("xor") |
-           tag!("shr") |
-           tag!("asr") |
-           tag!("shl") |
-           tag!("ifb") |
-           tag!("ifc") |
-           tag!("ife") |
-           tag!("ifn") |
-           tag!("ifg") |
-           tag!("ifa") |
-           tag!("ifl") |
-           tag!("ifu") |
-           tag!("adx") |
-           tag!("sbx") |
-           tag!("sti") |
-           tag!("std")
-       ), dcpu::BasicOp::from_str)
-);
-
-named!(parse_special_op<&str, dcpu::SpecialOp>,
-       map_res!(alt!(
-           tag!("jsr") |
-           tag!("int") |
-           tag!("iag") |
-           tag!("ias") |
-           tag!("rfi") |
-           tag!("iaq") |
-           tag!("hwn") |
-           tag!("hwq") |
-           tag!("hwi")
-       ), dcpu::SpecialOp::from_str)
+use nom::IResult;
+use nom::{tag, tag_no_case, map_res, named, alt, char, delimited, separated_pair, tuple, recognize, many0, pair, terminated, preceded, take_while1, none_of, opt};
+use nom::character::complete::{multispace0, alpha1, alphanumeric1, digit1, hex_digit1, oct_digit1, anychar};
+
+named!(parse_identifier<&str, &str>,
+       recognize!(pair!(alt!(alpha1 | tag!("_")), many0!(alt!(alphanumeric1 | tag!("_")))))
 );
 
+// Registers and keywords are matched as whole identifiers,
+// so that labels like `add_loop` are not read as register `a`.
 named!(parse_register<&str, dcpu::Register>,
-       map_res!(alt!(
-           tag!("a") |
-           tag!("b") |
-           tag!("c") |
-           tag!("x") |
-           tag!("y") |
-           tag!("z") |
-           tag!("i") |
-           tag!("j")
-       ), dcpu::Register::from_str)
-);
-
-named!(parse_value<&str, dcpu::Value>,
+       map_res!(parse_identifier, dcpu::Register::from_str)
+);
+
+named!(pub(crate) parse_label<&str, String>,
+       map_res!(parse_identifier, wrap_label)
+);
+
+named!(parse_value<&str, Operand>,
        alt!(
+           parse_pick |
            parse_simple_value |
            map_res!(parse_register, wrap_reg) |
-           map_res!(parse_number, wrap_next_word) |
+           map_res!(parse_expr, wrap_next_word) |
+           map_res!(delimited!(tuple!(char!('['), multispace0), parse_sp, tuple!(multispace0, char!(']'))), wrap_peek) |
+           map_res!(delimited!(tuple!(char!('['), multispace0), preceded!(tuple!(parse_sp, multispace0, char!('+'), multispace0), parse_expr), tuple!(multispace0, char!(']'))), wrap_pick) |
            map_res!(delimited!(tuple!(char!('['), multispace0), parse_register, tuple!(multispace0, char!(']'))), wrap_deref_reg) |
-           map_res!(delimited!(tuple!(char!('['), multispace0), separated_pair!(parse_register, tuple!(multispace0, char!('+'), multispace0), parse_number), tuple!(multispace0, char!(']'))), wrap_index_reg) |
-           map_res!(delimited!(tuple!(char!('['), multispace0), parse_number, tuple!(multispace0, char!(']'))), wrap_deref_next_word)
+           map_res!(delimited!(tuple!(char!('['), multispace0), separated_pair!(parse_register, tuple!(multispace0, char!('+'), multispace0), parse_expr), tuple!(multispace0, char!(']'))), wrap_index_reg) |
+           map_res!(delimited!(tuple!(char!('['), multispace0), separated_pair!(parse_expr, tuple!(multispace0, char!('+'), multispace0), parse_register), tuple!(multispace0, char!(']'))), wrap_reg_index) |
+           parse_inner_reg |
+           map_res!(delimited!(tuple!(char!('['), multispace0), parse_expr, tuple!(multispace0, char!(']'))), wrap_deref_next_word)
        )
 );
 
-named!(parse_simple_value<&str, dcpu::Value>,
-       map_res!(
-           alt!(tag!("stack") |
-                tag!("peek") |
-                tag!("pick") |
-                tag!("sp") |
-                tag!("pc") |
-                tag!("ex")), simple_value)
+// Register in the middle of the sum, [1 + b + 2]
+named!(parse_inner_reg<&str, Operand>,
+       map_res!(delimited!(tuple!(char!('['), multispace0),
+                           tuple!(terminated!(parse_expr, tuple!(multispace0, char!('+'), multispace0)),
+                                  terminated!(parse_register, multispace0),
+                                  map_res!(alt!(tag!("+") | tag!("-")), BinaryOp::from_str),
+                                  preceded!(multispace0, parse_expr)),
+                           tuple!(multispace0, char!(']'))),
+                wrap_inner_reg)
+);
+
+named!(parse_simple_value<&str, Operand>,
+       map_res!(parse_identifier, simple_value)
 );
 
+// PICK n
+named!(parse_pick<&str, Operand>,
+       map_res!(preceded!(tuple!(map_res!(parse_identifier, is_pick), multispace0), parse_expr), wrap_pick)
+);
+
+named!(parse_sp<&str, &str>,
+       map_res!(parse_identifier, is_sp)
+);
+
+// Operator precedence, lowest first: (== != < > <= >=) | ^ & (<< >>) (+ -) (* / %) unary
+named!(pub(crate) parse_expr<&str, Expr>,
+       map_res!(pair!(parse_or_expr, many0!(pair!(delimited!(multispace0, map_res!(alt!(tag!("==") | tag!("!=") | tag!("<=") | tag!(">=") | tag!("<") | tag!(">")), BinaryOp::from_str), multispace0), parse_or_expr))), fold_binary)
+);
+
+named!(parse_or_expr<&str, Expr>,
+       map_res!(pair!(parse_xor_expr, many0!(pair!(delimited!(multispace0, map_res!(tag!("|"), BinaryOp::from_str), multispace0), parse_xor_expr))), fold_binary)
+);
+
+named!(parse_xor_expr<&str, Expr>,
+       map_res!(pair!(parse_and_expr, many0!(pair!(delimited!(multispace0, map_res!(tag!("^"), BinaryOp::from_str), multispace0), parse_and_expr))), fold_binary)
+);
+
+named!(parse_and_expr<&str, Expr>,
+       map_res!(pair!(parse_shift_expr, many0!(pair!(delimited!(multispace0, map_res!(tag!("&"), BinaryOp::from_str), multispace0), parse_shift_expr))), fold_binary)
+);
+
+named!(parse_shift_expr<&str, Expr>,
+       map_res!(pair!(parse_sum_expr, many0!(pair!(delimited!(multispace0, map_res!(alt!(tag!("<<") | tag!(">>")), BinaryOp::from_str), multispace0), parse_sum_expr))), fold_binary)
+);
+
+named!(parse_sum_expr<&str, Expr>,
+       map_res!(pair!(parse_product_expr, many0!(pair!(delimited!(multispace0, map_res!(alt!(tag!("+") | tag!("-")), BinaryOp::from_str), multispace0), parse_product_expr))), fold_binary)
+);
+
+named!(parse_product_expr<&str, Expr>,
+       map_res!(pair!(parse_unary_expr, many0!(pair!(delimited!(multispace0, map_res!(alt!(tag!("*") | tag!("/") | tag!("%")), BinaryOp::from_str), multispace0), parse_unary_expr))), fold_binary)
+);
+
+named!(parse_unary_expr<&str, Expr>,
+       alt!(
+           map_res!(pair!(map_res!(alt!(tag!("-") | tag!("~")), UnaryOp::from_str), preceded!(multispace0, parse_unary_expr)), wrap_unary) |
+           parse_atom
+       )
+);
+
+named!(parse_atom<&str, Expr>,
+       alt!(
+           map_res!(parse_number, wrap_number) |
+           map_res!(parse_label_ref, wrap_label_ref) |
+           map_res!(recognize!(preceded!(char!(':'), alt!(take_while1!(is_minus) | take_while1!(is_plus)))), wrap_anonymous_ref) |
+           delimited!(tuple!(char!('('), multispace0), parse_expr, tuple!(multispace0, char!(')')))
+       )
+);
+
+fn fold_binary(tuple: (Expr, Vec<(BinaryOp, Expr)>)) -> Result<Expr, ()> {
+    let (first, rest) = tuple;
+    Ok(rest.into_iter().fold(first, |lhs, (op, rhs)| Expr::Binary(op, Box::new(lhs), Box::new(rhs))))
+}
+
+fn wrap_unary(tuple: (UnaryOp, Expr)) -> Result<Expr, ()> {
+    let (op, expr) = tuple;
+    Ok(Expr::Unary(op, Box::new(expr)))
+}
+
 named!(parse_number<&str, u16>,
-       map_res!(recognize!(nom::character::complete::digit1), u16::from_str)
+       alt!(
+           map_res!(preceded!(tag_no_case!("0x"), hex_digit1), from_hex) |
+           map_res!(preceded!(tag_no_case!("0b"), take_while1!(is_bin_digit)), from_bin) |
+           map_res!(preceded!(tag_no_case!("0o"), oct_digit1), from_oct) |
+           parse_char_literal |
+           map_res!(recognize!(digit1), u16::from_str)
+       )
+);
+
+named!(parse_char_literal<&str, u16>,
+       delimited!(
+           char!('\''),
+           alt!(
+               preceded!(char!('\\'), map_res!(anychar, unescape)) |
+               map_res!(none_of!("\\'"), wrap_char)
+           ),
+           char!('\'')
+       )
 );
 
-fn wrap_next_word(num: u16) -> Result<dcpu::Value, ()> {
-    Ok(dcpu::Value::NextWord(num))
+fn is_bin_digit(c: char) -> bool {
+    c == '0' || c == '1'
+}
+
+fn from_hex(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s, 16)
+}
+
+fn from_bin(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s, 2)
 }
 
-fn wrap_deref_next_word(num: u16) -> Result<dcpu::Value, ()> {
-    Ok(dcpu::Value::DerefNextWord(num))
+fn from_oct(s: &str) -> Result<u16, std::num::ParseIntError> {
+    u16::from_str_radix(s, 8)
 }
 
-fn wrap_index_reg(tuple: (dcpu::Register, u16)) -> Result<dcpu::Value, ()> {
+fn wrap_char(c: char) -> Result<u16, ()> {
+    if (c as u32) <= 0xffff {
+        Ok(c as u16)
+    } else {
+        Err(())
+    }
+}
+
+// Character following a backslash in character and string literals
+fn unescape(c: char) -> Result<u16, ()> {
+    match c {
+        'n' => Ok(0x0a),
+        'r' => Ok(0x0d),
+        't' => Ok(0x09),
+        '0' => Ok(0x00),
+        '\\' | '\'' | '"' => Ok(c as u16),
+        _ => Err(())
+    }
+}
+
+// Keywords are case-insensitive, labels are not
+fn is_reserved(s: &str) -> bool {
+    dcpu::Register::from_str(s).is_ok() || simple_value(s).is_ok() || is_pick(s).is_ok()
+}
+
+fn is_pick(s: &str) -> Result<&str, ()> {
+    if s.eq_ignore_ascii_case("pick") {
+        Ok(s)
+    } else {
+        Err(())
+    }
+}
+
+fn is_sp(s: &str) -> Result<&str, ()> {
+    if s.eq_ignore_ascii_case("sp") {
+        Ok(s)
+    } else {
+        Err(())
+    }
+}
+
+fn wrap_label(s: &str) -> Result<String, ()> {
+    if is_reserved(s) {
+        Err(())
+    } else {
+        Ok(String::from(s))
+    }
+}
+
+fn wrap_number(num: u16) -> Result<Expr, ()> {
+    Ok(Expr::Number(num))
+}
+
+fn wrap_label_ref(label: String) -> Result<Expr, ()> {
+    Ok(Expr::Label(label))
+}
+
+fn wrap_next_word(expr: Expr) -> Result<Operand, ()> {
+    Ok(Operand::NextWord(expr))
+}
+
+fn wrap_deref_next_word(expr: Expr) -> Result<Operand, ()> {
+    Ok(Operand::DerefNextWord(expr))
+}
+
+fn wrap_index_reg(tuple: (dcpu::Register, Expr)) -> Result<Operand, ()> {
     let (reg, index) = tuple;
-    Ok(dcpu::Value::IndexReg(reg, index))
+    Ok(Operand::IndexReg(reg, index))
+}
+
+fn wrap_reg_index(tuple: (Expr, dcpu::Register)) -> Result<Operand, ()> {
+    let (index, reg) = tuple;
+    Ok(Operand::IndexReg(reg, index))
+}
+
+fn wrap_inner_reg(tuple: (Expr, dcpu::Register, BinaryOp, Expr)) -> Result<Operand, ()> {
+    let (before, reg, op, after) = tuple;
+    Ok(Operand::IndexReg(reg, Expr::Binary(op, Box::new(before), Box::new(after))))
 }
 
-fn simple_value(s: &str) -> Result<dcpu::Value, ()> {
-    match s {
-        "stack" => Ok(dcpu::Value::STACK),
-        "peek" => Ok(dcpu::Value::PEEK),
-        "pick" => Ok(dcpu::Value::PICK),
-        "sp" => Ok(dcpu::Value::SP),
-        "pc" => Ok(dcpu::Value::PC),
-        "ex" => Ok(dcpu::Value::EX),
+fn wrap_peek(_: &str) -> Result<Operand, ()> {
+    Ok(Operand::PEEK)
+}
+
+fn wrap_pick(expr: Expr) -> Result<Operand, ()> {
+    Ok(Operand::PICK(expr))
+}
+
+fn simple_value(s: &str) -> Result<Operand, ()> {
+    match s.to_lowercase().as_str() {
+        "push" => Ok(Operand::PUSH),
+        "pop" => Ok(Operand::POP),
+        "peek" => Ok(Operand::PEEK),
+        "sp" => Ok(Operand::SP),
+        "pc" => Ok(Operand::PC),
+        "ex" => Ok(Operand::EX),
         _ => Err(())
     }
 }
 
-fn wrap_reg(reg: dcpu::Register) -> Result<dcpu::Value, ()> {
-    Ok(dcpu::Value::Reg(reg))
+fn wrap_reg(reg: dcpu::Register) -> Result<Operand, ()> {
+    Ok(Operand::Reg(reg))
 }
 
-fn wrap_deref_reg(reg: dcpu::Register) -> Result<dcpu::Value, ()> {
-    Ok(dcpu::Value::DerefReg(reg))
+fn wrap_deref_reg(reg: dcpu::Register) -> Result<Operand, ()> {
+    Ok(Operand::DerefReg(reg))
 }
 
-named!(parse_command<&str, dcpu::Command>,
-       complete!(delimited!(multispace0, alt!(parse_basic_command | parse_special_command), tuple!(multispace0, char!(';'), multispace0)))
+// `label:`, Notch-style `:label`, local `.label:` or anonymous `:`
+named!(pub(crate) parse_label_definition<&str, Statement>,
+       map_res!(alt!(
+           terminated!(parse_label, char!(':')) |
+           terminated!(parse_local_label, char!(':')) |
+           preceded!(char!(':'), parse_label) |
+           map_res!(char!(':'), wrap_anonymous)
+       ), wrap_label_definition)
+);
+
+named!(parse_local_label<&str, String>,
+       map_res!(recognize!(pair!(char!('.'), parse_identifier)), wrap_label)
+);
+
+// `label`, `.local` or `label.local` for a local label of another scope
+named!(parse_label_ref<&str, String>,
+       alt!(
+           map_res!(recognize!(tuple!(parse_label, char!('.'), parse_identifier)), wrap_label) |
+           parse_label |
+           parse_local_label
+       )
 );
 
-named!(parse_basic_command<&str, dcpu::Command>,
-       map_res!(separated_pair!(parse_basic_op, multispace0, separated_pair!(parse_value, tuple!(multispace0, char!(','), multispace0), parse_value)), wrap_basic)
+fn wrap_anonymous(_: char) -> Result<String, ()> {
+    Ok(String::from(":"))
+}
+
+// `:-` is the anonymous label before, `:+` the one after, repeated signs
+// skip further
+fn wrap_anonymous_ref(s: &str) -> Result<Expr, ()> {
+    Ok(Expr::Label(String::from(s)))
+}
+
+fn is_minus(c: char) -> bool {
+    c == '-'
+}
+
+fn is_plus(c: char) -> bool {
+    c == '+'
+}
+
+// Items of dat: 1, label + 2, "text", "zero terminated"z, "length prefixed"p,
+// "packed"k. Packed strings hold two 8-bit characters per word, high byte
+// first, an odd character leaves the low byte of the last word 0:
+//
+// dat "hello"k ; 0x6865, 0x6c6c, 0x6f00
+named!(parse_data_item<&str, Vec<Expr>>,
+       alt!(
+           map_res!(pair!(parse_string, opt!(alt!(tag_no_case!("z") | tag_no_case!("p") | tag_no_case!("k")))), wrap_string) |
+           map_res!(parse_expr, wrap_data_expr)
+       )
 );
 
-named!(parse_special_command<&str, dcpu::Command>,
-       map_res!(separated_pair!(parse_special_op, multispace0, parse_value), wrap_special)
+named!(pub(crate) parse_string<&str, Vec<u16>>,
+       delimited!(
+           char!('"'),
+           many0!(alt!(
+               preceded!(char!('\\'), map_res!(anychar, unescape)) |
+               map_res!(none_of!("\\\""), wrap_char)
+           )),
+           char!('"')
+       )
 );
 
-named!(pub parse_program<&str, Vec<dcpu::Command>>,
-       many0!(parse_command)
+fn wrap_string(tuple: (Vec<u16>, Option<&str>)) -> Result<Vec<Expr>, ()> {
+    let (string, kind) = tuple;
+    let mut words: Vec<Expr> = string.iter().copied().map(Expr::Number).collect();
+    match kind.map(|kind| kind.to_lowercase()).as_deref() {
+        Some("z") => words.push(Expr::Number(0)),
+        Some("p") => words.insert(0, Expr::Number(words.len() as u16)),
+        Some("k") => return pack(&string),
+        _ => {}
+    }
+    Ok(words)
+}
+
+// Characters past 0xff don't fit in a byte
+fn pack(string: &[u16]) -> Result<Vec<Expr>, ()> {
+    if string.iter().any(|c| *c > 0xff) {
+        return Err(());
+    }
+    Ok(string.chunks(2)
+        .map(|pair| Expr::Number(pair[0] << 8 | pair.get(1).copied().unwrap_or(0)))
+        .collect())
+}
+
+fn wrap_data_expr(expr: Expr) -> Result<Vec<Expr>, ()> {
+    Ok(vec![expr])
+}
+
+fn wrap_label_definition(label: String) -> Result<Statement, ()> {
+    Ok(Statement::Label(label))
+}
+
+// Mnemonics are identifiers, directives start with a dot
+named!(pub(crate) parse_mnemonic<&str, String>,
+       map_res!(recognize!(pair!(opt!(char!('.')), parse_identifier)), wrap_mnemonic)
 );
 
-fn wrap_basic(tuple: (dcpu::BasicOp, (dcpu::Value, dcpu::Value))) -> Result<dcpu::Command, ()> {
-    let (op, (b, a)) = tuple;
-    Ok(dcpu::Command::Basic{op, b, a})
+fn wrap_mnemonic(s: &str) -> Result<String, ()> {
+    Ok(String::from(s))
+}
+
+// Parses a whole source file. Statements are parsed one by one so that
+// a bad statement doesn't hide the errors in the ones after it.
+// Statements end at the end of the line and `;` starts a comment, unless
+// the legacy syntax is used, in which case statements end with `;` and
+// there are no comments.
+pub fn parse_program(file: &str, source: &str, options: &Options) -> Result<Vec<Located>, Vec<AssembleError>> {
+    let (statements, errors) = parse_statements(file, source, options);
+    if errors.is_empty() {
+        Ok(statements)
+    } else {
+        Err(errors)
+    }
+}
+
+// Statements of the lines that parsed and the errors of those that didn't
+pub fn parse_statements(file: &str, source: &str, options: &Options) -> (Vec<Located>, Vec<AssembleError>) {
+    let (lines, mut errors) = preprocess(file, source, options);
+    let mut statements = vec![];
+    for line in &lines {
+        let mut parser = Parser {
+            line,
+            options,
+            statements: &mut statements,
+            errors: &mut errors,
+            operands: vec![]
+        };
+        parser.line();
+    }
+    resolve_scopes(&mut statements, &mut errors);
+    (statements, errors)
+}
+
+struct Parser<'a> {
+    line: &'a Line,
+    options: &'a Options,
+    statements: &'a mut Vec<Located>,
+    errors: &'a mut Vec<AssembleError>,
+    // Spans of the expressions of the statement being parsed
+    operands: Vec<Span>
 }
 
-fn wrap_special(tuple: (dcpu::SpecialOp, dcpu::Value)) -> Result<dcpu::Command, ()> {
-    let (op, a) = tuple;
-    Ok(dcpu::Command::Special{op, a})
+impl<'a> Parser<'a> {
+    fn span(&self, at: &str) -> Span {
+        self.line.span_of(at)
+    }
+
+    fn push(&mut self, at: &str, statement: Statement) {
+        let span = self.span(at);
+        let operands = std::mem::take(&mut self.operands);
+        self.statements.push(Located { statement, span, operands });
+    }
+
+    // `count` expressions start at `at`
+    fn operand(&mut self, at: &str, count: usize) {
+        let span = self.span(at);
+        self.operands.extend(vec![span; count]);
+    }
+
+    fn error(&mut self, at: &str, kind: ErrorKind) {
+        let span = self.span(at);
+        self.errors.push(AssembleError::new(span, kind));
+    }
+
+    // Statements keep the `;` after them
+    fn line(&mut self) {
+        let text: &'a str = &self.line.text;
+        if self.options.legacy_syntax {
+            let mut rest = text;
+            while let Some(end) = find_outside_quotes(rest, |c| c == ';') {
+                self.statement(&rest[..=end]);
+                rest = &rest[end + 1..];
+            }
+            self.statement(rest);
+        } else {
+            match find_outside_quotes(text, |c| c == ';') {
+                Some(comment) => self.statement(&text[..=comment]),
+                None => self.statement(text)
+            }
+        }
+    }
+
+    // Labels followed by an optional command
+    fn statement(&mut self, chunk: &'a str) {
+        self.operands.clear();
+        let mut rest = chunk.trim_start();
+        while let Some((next, label)) = parse_complete(rest, parse_label_definition) {
+            self.push(rest, label);
+            rest = next.trim_start();
+        }
+        if rest.is_empty() || rest == ";" {
+            return;
+        }
+        if self.options.legacy_syntax && !rest.ends_with(';') {
+            return self.error(rest, ErrorKind::MissingSemicolon);
+        }
+        let (operands, mnemonic) = match parse_complete(rest, parse_mnemonic) {
+            Some(result) => result,
+            None => return self.error(rest, ErrorKind::UnknownMnemonic(first_word(rest)))
+        };
+        let name = mnemonic.to_lowercase();
+        let statement = if name == "dat" || name == ".dw" {
+            self.data(operands)
+        } else if name == ".define" {
+            self.define(operands)
+        } else if name == ".incbin" {
+            self.incbin(operands)
+        } else if name == ".org" {
+            self.expr(operands).map(Statement::Org)
+        } else if name == ".align" {
+            self.expr(operands).map(Statement::Align)
+        } else if name == ".reserve" {
+            self.expr(operands).map(Statement::Reserve)
+        } else if name == ".fill" {
+            self.fill(operands)
+        } else if name == ".section" {
+            self.item(operands, parse_label).and_then(|(section, rest)| {
+                self.end(rest)?;
+                Some(Statement::Section(section))
+            })
+        } else if name == ".global" {
+            self.names(operands).map(Statement::Global)
+        } else if name == ".extern" {
+            self.names(operands).map(Statement::Extern)
+        } else if let Ok(op) = dcpu::BasicOp::from_str(&name) {
+            self.basic(op, &mnemonic, operands)
+        } else if let Ok(op) = dcpu::SpecialOp::from_str(&name) {
+            self.special(op, &mnemonic, operands)
+        } else {
+            return self.error(rest, ErrorKind::UnknownMnemonic(mnemonic));
+        };
+        if let Some(statement) = statement {
+            self.push(rest, statement);
+        }
+    }
+
+    fn basic(&mut self, op: dcpu::BasicOp, mnemonic: &str, text: &'a str) -> Option<Statement> {
+        let b_text = text.trim_start();
+        let (b, rest) = self.item(text, parse_value)?;
+        let rest = self.comma(rest)?;
+        let a_text = rest.trim_start();
+        let (a, rest) = self.item(rest, parse_value)?;
+        self.end(rest)?;
+        // Writes to a literal are silently dropped by the CPU
+        if let Operand::NextWord(_) = b {
+            if !op.is_conditional() {
+                let reason = format!("`{}` can't write to a literal", mnemonic);
+                self.error(b_text, ErrorKind::IllegalOperand(reason));
+                return None;
+            }
+        }
+        self.stack_operand(b_text, &b, true)?;
+        self.stack_operand(a_text, &a, false)?;
+        self.operand(b_text, b.expr().into_iter().count());
+        self.operand(a_text, a.expr().into_iter().count());
+        Some(Statement::Basic { op, b, a })
+    }
+
+    fn special(&mut self, op: dcpu::SpecialOp, mnemonic: &str, text: &'a str) -> Option<Statement> {
+        let a_text = text.trim_start();
+        let (a, rest) = self.item(text, parse_value)?;
+        self.end(rest)?;
+        // IAG and HWN store their result in a
+        if let Operand::NextWord(_) = a {
+            if matches!(op, dcpu::SpecialOp::IAG | dcpu::SpecialOp::HWN) {
+                let reason = format!("`{}` can't write to a literal", mnemonic);
+                self.error(a_text, ErrorKind::IllegalOperand(reason));
+                return None;
+            }
+        }
+        self.stack_operand(a_text, &a, false)?;
+        self.operand(a_text, a.expr().into_iter().count());
+        Some(Statement::Special { op, a })
+    }
+
+    // PUSH and POP share an encoding that pushes in b and pops in a
+    fn stack_operand(&mut self, text: &str, operand: &Operand, in_b: bool) -> Option<()> {
+        let reason = match operand {
+            Operand::POP if in_b => "`pop` can only be used as a",
+            Operand::PUSH if !in_b => "`push` can only be used as b",
+            _ => return Some(())
+        };
+        self.error(text, ErrorKind::IllegalOperand(String::from(reason)));
+        None
+    }
+
+    // .define NAME expr
+    fn define(&mut self, text: &'a str) -> Option<Statement> {
+        let name_text = text.trim_start();
+        let (rest, name) = match parse_complete(name_text, parse_label) {
+            Some(result) => result,
+            None if at_end(name_text) => {
+                self.error(text, ErrorKind::MissingOperand);
+                return None;
+            },
+            None => {
+                self.error(name_text, ErrorKind::BadOperand(first_word(name_text)));
+                return None;
+            }
+        };
+        let expr_text = rest.trim_start();
+        let (expr, rest) = self.item(rest, parse_expr)?;
+        self.end(rest)?;
+        self.operand(expr_text, 1);
+        Some(Statement::Define(name, expr))
+    }
+
+    // Directive with a single expression
+    fn expr(&mut self, text: &'a str) -> Option<Expr> {
+        let (expr, rest) = self.item(text, parse_expr)?;
+        self.end(rest)?;
+        self.operand(text.trim_start(), 1);
+        Some(expr)
+    }
+
+    // Comma separated symbol names
+    fn names(&mut self, text: &'a str) -> Option<Vec<String>> {
+        let mut names = vec![];
+        let mut rest = text;
+        loop {
+            let (name, next) = self.item(rest, parse_label)?;
+            names.push(name);
+            if !next.trim_start().starts_with(',') {
+                self.end(next)?;
+                return Some(names);
+            }
+            rest = self.comma(next)?;
+        }
+    }
+
+    // .fill count, value
+    fn fill(&mut self, text: &'a str) -> Option<Statement> {
+        let (count, rest) = self.item(text, parse_expr)?;
+        let rest = self.comma(rest)?;
+        let value_text = rest.trim_start();
+        let (value, rest) = self.item(rest, parse_expr)?;
+        self.end(rest)?;
+        self.operand(text.trim_start(), 1);
+        self.operand(value_text, 1);
+        Some(Statement::Fill(count, value))
+    }
+
+    // .incbin "file" with an optional byte order, `big` (the default) or
+    // `little`. Every two bytes make a word, an odd last byte is padded.
+    fn incbin(&mut self, text: &'a str) -> Option<Statement> {
+        let (name, rest) = self.item(text, parse_string)?;
+        let name = String::from_utf16_lossy(&name);
+        let rest = rest.trim_start();
+        let (little_endian, rest) = match rest.strip_prefix(',') {
+            Some(order) => {
+                let order = order.trim_start();
+                match parse_complete(order, parse_mnemonic) {
+                    Some((rest, word)) if word.eq_ignore_ascii_case("big") => (false, rest),
+                    Some((rest, word)) if word.eq_ignore_ascii_case("little") => (true, rest),
+                    _ => {
+                        self.error(order, ErrorKind::BadOperand(until_separator(order)));
+                        return None;
+                    }
+                }
+            },
+            None => (false, rest)
+        };
+        self.end(rest)?;
+        let path = match resolve(&self.line.file, &name, &self.options.include_paths) {
+            Some(path) => path,
+            None => {
+                self.error(text.trim_start(), ErrorKind::FileNotFound(name));
+                return None;
+            }
+        };
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                let kind = ErrorKind::CantRead { path: path.display().to_string(), message: error.to_string() };
+                self.error(text.trim_start(), kind);
+                return None;
+            }
+        };
+        let words = bytes.chunks(2)
+            .map(|pair| {
+                let pair = [pair[0], *pair.get(1).unwrap_or(&0)];
+                let word = if little_endian { u16::from_le_bytes(pair) } else { u16::from_be_bytes(pair) };
+                Expr::Number(word)
+            })
+            .collect();
+        Some(Statement::Data(words))
+    }
+
+    fn data(&mut self, text: &'a str) -> Option<Statement> {
+        let mut words = vec![];
+        let mut rest = text;
+        loop {
+            let (item, next) = self.item(rest, parse_data_item)?;
+            self.operand(rest.trim_start(), item.len());
+            words.extend(item);
+            if let Some(next) = next.trim_start().strip_prefix(',') {
+                rest = next;
+            } else {
+                self.end(next)?;
+                return Some(Statement::Data(words));
+            }
+        }
+    }
+
+    // Parses one operand or data item, returning it and the text after it
+    fn item<T>(&mut self, text: &'a str, parser: fn(&str) -> IResult<&str, T>) -> Option<(T, &'a str)> {
+        let trimmed = text.trim_start();
+        if trimmed.starts_with(',') {
+            self.error(trimmed, ErrorKind::MissingOperand);
+            return None;
+        }
+        if at_end(trimmed) {
+            self.error(text, ErrorKind::MissingOperand);
+            return None;
+        }
+        match parse_complete(trimmed, parser) {
+            Some((rest, item)) => Some((item, rest)),
+            None => {
+                self.error(trimmed, ErrorKind::BadOperand(until_separator(trimmed)));
+                None
+            }
+        }
+    }
+
+    fn comma(&mut self, rest: &'a str) -> Option<&'a str> {
+        let trimmed = rest.trim_start();
+        if let Some(next) = trimmed.strip_prefix(',') {
+            Some(next)
+        } else if at_end(trimmed) {
+            self.error(rest, ErrorKind::MissingOperand);
+            None
+        } else {
+            self.error(trimmed, ErrorKind::TrailingGarbage(until_separator(trimmed)));
+            None
+        }
+    }
+
+    fn end(&mut self, rest: &'a str) -> Option<()> {
+        let trimmed = rest.trim_start();
+        if at_end(trimmed) {
+            Some(())
+        } else {
+            let garbage = trimmed.trim_end().trim_end_matches(';').trim_end();
+            self.error(trimmed, ErrorKind::TrailingGarbage(String::from(garbage)));
+            None
+        }
+    }
+}
+
+// Runs `parser` on `text` as if the statement ended right after it. The
+// streaming parsers can't tell the end of the input from a token that
+// isn't complete yet.
+pub(crate) fn parse_complete<T>(text: &str, parser: fn(&str) -> IResult<&str, T>) -> Option<(&str, T)> {
+    let padded = format!("{};", text);
+    match parser(&padded) {
+        Ok((rest, result)) => {
+            let consumed = (padded.len() - rest.len()).min(text.len());
+            Some((&text[consumed..], result))
+        },
+        Err(_) => None
+    }
+}
+
+// Whether only the end of the statement is left, `rest` starts after
+// whitespace
+fn at_end(rest: &str) -> bool {
+    rest.is_empty() || rest.starts_with(';')
+}
+
+// Byte index of the first character matching `pred` outside of string and
+// character literals
+pub(crate) fn find_outside_quotes<P: FnMut(char) -> bool>(text: &str, mut pred: P) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {},
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if pred(c) => return Some(i),
+            None => {}
+        }
+    }
+    None
+}
+
+// Text up to the next operand separator, for error messages
+fn until_separator(text: &str) -> String {
+    let end = find_outside_quotes(text, |c| c == ',' || c == ';').unwrap_or(text.len());
+    String::from(text[..end].trim())
+}
+
+pub(crate) fn first_word(text: &str) -> String {
+    let end = text.find(|c: char| c.is_whitespace() || c == ',' || c == ';').unwrap_or(text.len());
+    String::from(&text[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembly;
+
+    fn assemble(source: &str) -> Vec<u16> {
+        assembly::generate_code(assembly::parse(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn data_strings() {
+        assert_eq!(assemble(r#"dat "hi", 1"#), [0x68, 0x69, 1]);
+        assert_eq!(assemble(r#"dat "hi"z"#), [0x68, 0x69, 0]);
+        assert_eq!(assemble(r#"dat "hi"p"#), [2, 0x68, 0x69]);
+        assert_eq!(assemble(r#"dat "a\n"z"#), [0x61, 0x0a, 0]);
+    }
+
+    #[test]
+    fn packed_data_strings() {
+        assert_eq!(assemble(r#"dat "hello"k"#), [0x6865, 0x6c6c, 0x6f00]);
+        assert_eq!(assemble(r#"dat "hi"k, "!"k"#), [0x6869, 0x2100]);
+        assert!(assembly::parse("dat \"\u{3b1}\"k").is_err());
+    }
+
+    #[test]
+    fn number_and_character_literals() {
+        assert_eq!(assemble("dat 0x8000, 0XfF, 0b1010, 0B11, 0o17, 0O7, 42"), [0x8000, 0xff, 10, 3, 15, 7, 42]);
+        assert_eq!(assemble("dat 'A', '\\n', '\\'', ' '"), [0x41, 0x0a, 0x27, 0x20]);
+        assert_eq!(assemble("dat -1, -30, 65535"), [0xffff, 0xffe2, 0xffff]);
+        assert!(assembly::parse("dat 65536").is_err());
+        assert!(assembly::parse("dat 0x10000").is_err());
+        assert!(assembly::parse("dat 0b2").is_err());
+        assert!(assembly::parse("dat 0o8").is_err());
+    }
+
+    #[test]
+    fn keywords_are_case_insensitive() {
+        assert_eq!(assemble("SET A, 0x30\nIFE [B + 1], PC\nJSR PEEK"), assemble("set a, 0x30\nife [b + 1], pc\njsr peek"));
+        assert_eq!(assemble("Set PC, Pop\nDAT 1"), assemble("set pc, pop\ndat 1"));
+    }
+
+    #[test]
+    fn register_anywhere_in_index_sum() {
+        let code = assemble("set a, [b + 3]");
+        assert_eq!(assemble("set a, [1 + b + 2]"), code);
+        assert_eq!(assemble("set a, [b + 1 + 2]"), code);
+        assert_eq!(assemble("set a, [1 + 2 + b]"), code);
+        assert_eq!(assemble("set a, [5 + b - 2]"), code);
+        assert_eq!(assemble("set [1+b+2], a"), assemble("set [b + 3], a"));
+        assert!(assembly::parse("set a, [1 + b + c]").is_err());
+    }
+
+    #[test]
+    fn labels_are_case_sensitive() {
+        assert_eq!(assemble("Loop:\nset pc, loop\nloop:\nset pc, Loop"), assemble("set pc, 1\nset pc, 0"));
+    }
+
+    fn assemble_legacy(source: &str) -> Result<Vec<u16>, Vec<assembly::AssembleError>> {
+        let options = assembly::Options { legacy_syntax: true, ..assembly::Options::default() };
+        assembly::assemble_source("t.s", source, &options)
+    }
+
+    #[test]
+    fn comments_and_newline_terminated_statements() {
+        let code = [0x7c01, 0x1234, 0x8822, 0x2d];
+        assert_eq!(assemble("set a, 0x1234\nadd b, 1\ndat 0x2d"), code);
+        assert_eq!(assemble("; header\n  set a, 0x1234 ; load\nadd b, 1;\n\n\tdat 0x2d ; tail ;;"), code);
+        assert_eq!(assemble("start: ; nothing else\nset pc, start"), [0x8781]);
+        // `;` in strings and characters doesn't start a comment
+        assert_eq!(assemble("dat \";\", ';' ; both"), [0x3b, 0x3b]);
+        assert!(assembly::parse("set a, 1 set b, 2").is_err());
+    }
+
+    #[test]
+    fn legacy_statements_end_with_semicolons() {
+        assert_eq!(assemble_legacy("set a, 0x1234; add b, 1;\ndat 0x2d;").unwrap(), [0x7c01, 0x1234, 0x8822, 0x2d]);
+        assert_eq!(assemble_legacy("dat \";\";").unwrap(), [0x3b]);
+        let errors = assemble_legacy("set a, 1;\nset b, 2").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].span.line, &errors[0].kind), (2, &assembly::ErrorKind::MissingSemicolon));
+        // Comments are just a syntax error
+        assert!(assemble_legacy("set a, 1; comment").is_err());
+    }
+
+    // Line and column of each error
+    fn error_positions(source: &str) -> Vec<(usize, usize)> {
+        let errors = assembly::assemble_source("t.s", source, &assembly::Options::default()).unwrap_err();
+        errors.iter().map(|error| (error.span.line, error.span.column)).collect()
+    }
+
+    #[test]
+    fn errors_point_at_the_operand() {
+        assert_eq!(error_positions("    set 1, a"), [(1, 9)]);
+        assert_eq!(error_positions("    add a, missing"), [(1, 12)]);
+        assert_eq!(error_positions("set a, 1\n  set [b + 1 / 0], a"), [(2, 7)]);
+        assert_eq!(error_positions("dat 1, \"ab\", missing"), [(1, 14)]);
+        assert_eq!(error_positions(".fill 2, 1 / 0"), [(1, 10)]);
+    }
+
+    #[test]
+    fn special_ops_reject_literal_destinations() {
+        assert_eq!(error_positions("iag 5"), [(1, 5)]);
+        assert_eq!(error_positions("hwn 0x1000"), [(1, 5)]);
+        assert_eq!(assemble("ias 5\nhwq 0x1000"), [0x9940, 0x7e20, 0x1000]);
+    }
 }
@@ -0,0 +1,606 @@
+use crate::assembly::{AssembleError, ErrorKind, Options, Span, Statement};
+use crate::assembly::parser::{find_outside_quotes, first_word, parse_complete, parse_expr, parse_label, parse_label_definition, parse_mnemonic, parse_string};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Source line after preprocessing. In legacy syntax a statement can span
+// several lines, they are joined into one Line.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub file: String,
+    pub number: usize,
+    pub text: String,
+    pub expanded_from: Option<Box<Span>>
+}
+
+impl Line {
+    // Span of `at`, which has to be a slice of the text
+    pub fn span_of(&self, at: &str) -> Span {
+        let offset = at.as_ptr() as usize - self.text.as_ptr() as usize;
+        let mut span = Span::new(&self.file, &self.text, offset);
+        span.line += self.number - 1;
+        span.expanded_from = self.expanded_from.clone();
+        span
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Line>
+}
+
+// State of an .if block
+struct Condition {
+    active: bool, // lines are assembled
+    taken: bool, // a branch was or can't be assembled, the rest are skipped
+    has_else: bool,
+    span: Span
+}
+
+// Macros can call macros, this stops runaway recursion
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+const DIRECTIVES: [&str; 10] = [".define", ".macro", ".endmacro", ".if", ".elif", ".else", ".endif", ".rep", ".endrep", ".include"];
+
+// Expands includes, macros, .rep blocks and .if blocks. Defines are passed
+// on as statements, but their values are also tracked here for .if and .rep.
+pub fn preprocess(file: &str, source: &str, options: &Options) -> (Vec<Line>, Vec<AssembleError>) {
+    let mut preprocessor = Preprocessor {
+        options,
+        includes: fs::canonicalize(file).into_iter().collect(),
+        defines: HashMap::new(),
+        macros: HashMap::new(),
+        depth: 0,
+        expansions: 0,
+        lines: vec![],
+        errors: vec![]
+    };
+    preprocessor.run(&lines(file, source));
+    (preprocessor.lines, preprocessor.errors)
+}
+
+fn lines(file: &str, source: &str) -> Vec<Line> {
+    source.lines().enumerate()
+        .map(|(i, text)| Line {
+            file: String::from(file),
+            number: i + 1,
+            text: String::from(text),
+            expanded_from: None
+        })
+        .collect()
+}
+
+// Finds `name` next to `from`, the file that refers to it, or else in one
+// of the include paths
+pub fn resolve(from: &str, name: &str, include_paths: &[PathBuf]) -> Option<PathBuf> {
+    let local = Path::new(from).parent().map(|dir| dir.join(name));
+    local.into_iter()
+        .chain(include_paths.iter().map(|dir| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+struct Preprocessor<'a> {
+    options: &'a Options,
+    // Files being included, innermost last
+    includes: Vec<PathBuf>,
+    defines: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    depth: usize,
+    // Macro calls expanded so far, numbers the labels of each expansion
+    expansions: usize,
+    lines: Vec<Line>,
+    errors: Vec<AssembleError>
+}
+
+impl<'a> Preprocessor<'a> {
+    fn error(&mut self, line: &Line, at: &str, kind: ErrorKind) {
+        self.errors.push(AssembleError::new(line.span_of(at), kind));
+    }
+
+    // .if blocks have to be closed in the same file, macro or .rep block
+    fn run(&mut self, input: &[Line]) {
+        let mut conditions: Vec<Condition> = vec![];
+        let mut i = 0;
+        while i < input.len() {
+            let line = &input[i];
+            i += 1;
+            let active = match conditions.last() {
+                Some(condition) => condition.active,
+                None => true
+            };
+            let (name, args) = match self.directive(line) {
+                Some(directive) => directive,
+                None => {
+                    if active {
+                        self.statement(line);
+                    }
+                    continue;
+                }
+            };
+            match name.as_str() {
+                ".if" => {
+                    let value = active && self.condition(line, args);
+                    conditions.push(Condition {
+                        active: value,
+                        taken: value || !active,
+                        has_else: false,
+                        span: line.span_of(args)
+                    });
+                },
+                ".elif" | ".else" => match conditions.last_mut() {
+                    Some(condition) if !condition.has_else => {
+                        if condition.taken {
+                            condition.active = false;
+                        } else if name == ".else" {
+                            condition.active = true;
+                            condition.taken = true;
+                        } else {
+                            condition.active = self.condition(line, args);
+                            condition.taken = condition.active;
+                        }
+                        condition.has_else = name == ".else";
+                    },
+                    _ => self.error(line, &line.text, ErrorKind::UnexpectedDirective(name))
+                },
+                ".endif" => {
+                    if conditions.pop().is_none() {
+                        self.error(line, &line.text, ErrorKind::UnexpectedDirective(name));
+                    }
+                },
+                _ if !active => {},
+                ".macro" => match block(input, i, ".macro", ".endmacro") {
+                    Some(end) => {
+                        self.define_macro(line, args, &input[i..end]);
+                        i = end + 1;
+                    },
+                    None => {
+                        self.error(line, &line.text, ErrorKind::UnterminatedBlock(name));
+                        i = input.len();
+                    }
+                },
+                ".rep" => match block(input, i, ".rep", ".endrep") {
+                    Some(end) => {
+                        let count = self.constant(line, args).unwrap_or(0);
+                        for _ in 0..count {
+                            self.run(&input[i..end]);
+                        }
+                        i = end + 1;
+                    },
+                    None => {
+                        self.error(line, &line.text, ErrorKind::UnterminatedBlock(name));
+                        i = input.len();
+                    }
+                },
+                ".define" => {
+                    self.define(args);
+                    self.emit(line.clone());
+                },
+                ".include" => self.include(line, args),
+                _ => self.error(line, &line.text, ErrorKind::UnexpectedDirective(name))
+            }
+        }
+        for condition in conditions {
+            self.errors.push(AssembleError::new(condition.span, ErrorKind::UnterminatedBlock(String::from(".if"))));
+        }
+    }
+
+    // Lowercase name and arguments of a preprocessor directive
+    fn directive<'l>(&self, line: &'l Line) -> Option<(String, &'l str)> {
+        let code = self.code(&line.text).trim_start();
+        let (args, name) = parse_complete(code, parse_mnemonic)?;
+        let name = name.to_lowercase();
+        if DIRECTIVES.contains(&name.as_str()) {
+            Some((name, args))
+        } else {
+            None
+        }
+    }
+
+    // Text without the comment, or without the `;` in legacy syntax
+    fn code<'t>(&self, text: &'t str) -> &'t str {
+        if self.options.legacy_syntax {
+            let text = text.trim_end();
+            text.strip_suffix(';').unwrap_or(text)
+        } else {
+            match find_outside_quotes(text, |c| c == ';') {
+                Some(comment) => &text[..comment],
+                None => text
+            }
+        }
+    }
+
+    fn condition(&mut self, line: &Line, text: &str) -> bool {
+        matches!(self.constant(line, text), Some(value) if value != 0)
+    }
+
+    // Evaluates an expression that may only use numbers and defines
+    fn constant(&mut self, line: &Line, text: &str) -> Option<u16> {
+        let text = text.trim();
+        if text.is_empty() {
+            self.error(line, text, ErrorKind::MissingOperand);
+            return None;
+        }
+        let expr = match parse_complete(text, parse_expr) {
+            Some((rest, expr)) if rest.trim().is_empty() => expr,
+            _ => {
+                self.error(line, text, ErrorKind::BadOperand(String::from(text)));
+                return None;
+            }
+        };
+        match expr.eval(&self.defines) {
+            Ok(value) => Some(value),
+            Err(ErrorKind::UndefinedLabel(name)) => {
+                self.error(line, text, ErrorKind::NotConstant(name));
+                None
+            },
+            Err(kind) => {
+                self.error(line, text, kind);
+                None
+            }
+        }
+    }
+
+    // Only remembers the value, the statement reports any errors
+    fn define(&mut self, text: &str) {
+        if let Some((rest, name)) = parse_complete(text.trim_start(), parse_label) {
+            if let Some((rest, expr)) = parse_complete(rest.trim_start(), parse_expr) {
+                if let (true, Ok(value)) = (rest.trim().is_empty(), expr.eval(&self.defines)) {
+                    self.defines.insert(name, value);
+                }
+            }
+        }
+    }
+
+    // .include "file", the lines of the file are preprocessed in place
+    fn include(&mut self, line: &Line, text: &str) {
+        let text = text.trim();
+        let name = match parse_complete(text, parse_string) {
+            Some((rest, name)) if rest.trim().is_empty() => String::from_utf16_lossy(&name),
+            _ => return self.error(line, text, ErrorKind::BadOperand(String::from(text)))
+        };
+        let path = match resolve(&line.file, &name, &self.options.include_paths) {
+            Some(path) => path,
+            None => return self.error(line, text, ErrorKind::FileNotFound(name))
+        };
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if self.includes.contains(&canonical) {
+            return self.error(line, text, ErrorKind::IncludeCycle(path.display().to_string()));
+        }
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(error) => {
+                let kind = ErrorKind::CantRead { path: path.display().to_string(), message: error.to_string() };
+                return self.error(line, text, kind);
+            }
+        };
+        self.includes.push(canonical);
+        self.run(&lines(&path.display().to_string(), &source));
+        self.includes.pop();
+    }
+
+    // .macro name(param, ...)
+    fn define_macro(&mut self, line: &Line, text: &str, body: &[Line]) {
+        let text = text.trim_start();
+        let (rest, name) = match parse_complete(text, parse_label) {
+            Some(result) => result,
+            None => return self.error(line, text, ErrorKind::BadOperand(first_word(text)))
+        };
+        let params = match arguments(rest) {
+            Some(params) => params,
+            None => return self.error(line, rest, ErrorKind::BadOperand(String::from(rest.trim())))
+        };
+        if self.macros.contains_key(&name) {
+            return self.error(line, text, ErrorKind::DuplicateMacro(name));
+        }
+        self.macros.insert(name, Macro { params, body: body.to_vec() });
+    }
+
+    // Labels followed by either a macro call or a statement
+    fn statement(&mut self, line: &Line) {
+        let code = self.code(&line.text);
+        let mut rest = code.trim_start();
+        while let Some((next, _)) = parse_complete(rest, parse_label_definition) {
+            rest = next.trim_start();
+        }
+        let (args, name) = match parse_complete(rest, parse_label) {
+            Some(result) if self.macros.contains_key(&result.1) => result,
+            _ => return self.emit(line.clone())
+        };
+        // Labels before the call stay on a line of their own
+        let labels = &line.text[..rest.as_ptr() as usize - line.text.as_ptr() as usize];
+        if !labels.trim().is_empty() {
+            self.emit(Line { text: String::from(labels), ..line.clone() });
+        }
+        let args = match arguments(args) {
+            Some(args) => args,
+            None => return self.error(line, args, ErrorKind::BadOperand(String::from(args.trim())))
+        };
+        let expected = self.macros[&name].params.len();
+        if args.len() != expected {
+            let found = args.len();
+            return self.error(line, rest, ErrorKind::MacroArguments { name, expected, found });
+        }
+        if self.depth >= MAX_EXPANSION_DEPTH {
+            return self.error(line, rest, ErrorKind::MacroDepth(name));
+        }
+        let call = Box::new(line.span_of(rest));
+        self.expansions += 1;
+        let definition = &self.macros[&name];
+        // Labels of the body are renamed so that every expansion has its
+        // own, `loop` becomes `loop__1`, `loop__2`, ...
+        let labels = body_labels(&definition.body, &definition.params);
+        let renamed: Vec<String> = labels.iter().map(|label| format!("{}__{}", label, self.expansions)).collect();
+        let body: Vec<Line> = definition.body.iter()
+            .map(|body_line| Line {
+                text: substitute(&substitute(&body_line.text, &labels, &renamed), &definition.params, &args),
+                expanded_from: Some(call.clone()),
+                ..body_line.clone()
+            })
+            .collect();
+        self.depth += 1;
+        self.run(&body);
+        self.depth -= 1;
+    }
+
+    // In legacy syntax a statement that doesn't end on its line continues
+    // on the next one
+    fn emit(&mut self, line: Line) {
+        if self.options.legacy_syntax {
+            if let Some(last) = self.lines.last_mut() {
+                let next = last.number + last.text.matches('\n').count() + 1;
+                if unterminated(&last.text) && last.file == line.file && next == line.number && last.expanded_from == line.expanded_from {
+                    last.text.push('\n');
+                    last.text.push_str(&line.text);
+                    return;
+                }
+            }
+        }
+        self.lines.push(line);
+    }
+}
+
+// Index of the line closing the block that starts at `start`, blocks of
+// the same kind can be nested
+fn block(input: &[Line], start: usize, open: &str, close: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, line) in input.iter().enumerate().skip(start) {
+        let name = match parse_complete(line.text.trim_start(), parse_mnemonic) {
+            Some((_, name)) => name.to_lowercase(),
+            None => continue
+        };
+        if name == open {
+            depth += 1;
+        } else if name == close {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// `(a, b)`, `a, b` or nothing, split at commas outside of brackets
+fn arguments(text: &str) -> Option<Vec<String>> {
+    let text = text.trim();
+    let text = match text.strip_prefix('(') {
+        Some(inner) => inner.strip_suffix(')')?,
+        None => text
+    };
+    if text.trim().is_empty() {
+        return Some(vec![]);
+    }
+    let mut args = vec![];
+    let mut rest = text;
+    loop {
+        let mut depth = 0;
+        let end = find_outside_quotes(rest, |c| {
+            match c {
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth -= 1,
+                ',' => return depth == 0,
+                _ => {}
+            }
+            false
+        });
+        match end {
+            Some(end) => {
+                args.push(String::from(rest[..end].trim()));
+                rest = &rest[end + 1..];
+            },
+            None => {
+                args.push(String::from(rest.trim()));
+                break;
+            }
+        }
+    }
+    if args.iter().any(|arg| arg.is_empty()) {
+        None
+    } else {
+        Some(args)
+    }
+}
+
+// Names of the labels defined at the start of the lines, without the `.`
+// of local labels. Labels named by a parameter are left to the caller.
+fn body_labels(body: &[Line], params: &[String]) -> Vec<String> {
+    let mut labels = vec![];
+    for line in body {
+        let mut rest = line.text.trim_start();
+        while let Some((next, label)) = parse_complete(rest, parse_label_definition) {
+            if let Statement::Label(label) = label {
+                let name = String::from(label.trim_start_matches(['.', ':']));
+                if !name.is_empty() && !params.contains(&name) && !labels.contains(&name) {
+                    labels.push(name);
+                }
+            }
+            rest = next.trim_start();
+        }
+    }
+    labels
+}
+
+// Replaces whole identifiers outside of literals
+fn substitute(text: &str, params: &[String], args: &[String]) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let code_end = rest.find(['"', '\'']).unwrap_or(rest.len());
+        let mut code = &rest[..code_end];
+        while let Some(start) = code.find(|c: char| c.is_alphanumeric() || c == '_') {
+            result.push_str(&code[..start]);
+            let word_end = code[start..].find(|c: char| !(c.is_alphanumeric() || c == '_')).map_or(code.len(), |end| start + end);
+            let word = &code[start..word_end];
+            match params.iter().position(|param| param == word) {
+                Some(i) => result.push_str(&args[i]),
+                None => result.push_str(word)
+            }
+            code = &code[word_end..];
+        }
+        result.push_str(code);
+        rest = &rest[code_end..];
+        // Copy the literal as is
+        if let Some(quote) = rest.chars().next() {
+            let end = literal_end(rest, quote);
+            result.push_str(&rest[..end]);
+            rest = &rest[end..];
+        }
+    }
+    result
+}
+
+// Length of the string or character literal at the start of `text`
+fn literal_end(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return i + 1;
+        }
+    }
+    text.len()
+}
+
+// Whether the text ends in a statement without its `;`
+fn unterminated(text: &str) -> bool {
+    let mut rest = text;
+    while let Some(end) = find_outside_quotes(rest, |c| c == ';') {
+        rest = &rest[end + 1..];
+    }
+    !rest.trim().is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembly::{self, ErrorKind};
+
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn assemble(source: &str) -> Result<Vec<u16>, Vec<assembly::AssembleError>> {
+        assembly::assemble_source("t.s", source, &assembly::Options::default())
+    }
+
+    // Empty directory of its own for every test
+    fn directory(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dcpu16-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        dir
+    }
+
+    // Assembles `main.s` in `dir` with `dir/lib` as include path
+    fn assemble_file(dir: &Path, source: &str) -> Result<Vec<u16>, Vec<assembly::AssembleError>> {
+        let main = dir.join("main.s");
+        fs::write(&main, source).unwrap();
+        let options = assembly::Options { include_paths: vec![dir.join("lib")], ..assembly::Options::default() };
+        assembly::assemble_source(&main.display().to_string(), source, &options)
+    }
+
+    #[test]
+    fn includes_are_searched_next_to_the_file_then_in_include_paths() {
+        let dir = directory("include");
+        fs::write(dir.join("local.s"), "local: dat 1\n.include \"shared.s\"").unwrap();
+        fs::write(dir.join("lib/shared.s"), "dat 2").unwrap();
+        fs::write(dir.join("lib/local.s"), "dat 3").unwrap();
+        let result = assemble_file(&dir, ".include \"local.s\"\nset pc, local");
+        let missing = assemble_file(&dir, ".include \"missing.s\"");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result.unwrap(), [1, 2, 0x8781]);
+        assert_eq!(missing.unwrap_err()[0].kind, ErrorKind::FileNotFound(String::from("missing.s")));
+    }
+
+    #[test]
+    fn cyclic_includes_are_errors() {
+        let dir = directory("include-cycle");
+        fs::write(dir.join("a.s"), ".include \"b.s\"").unwrap();
+        fs::write(dir.join("b.s"), "dat 1\n.include \"a.s\"").unwrap();
+        let cycle = assemble_file(&dir, ".include \"a.s\"");
+        let itself = assemble_file(&dir, ".include \"main.s\"");
+        fs::remove_dir_all(&dir).unwrap();
+        let errors = cycle.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0].kind, ErrorKind::IncludeCycle(path) if path.ends_with("a.s")));
+        assert_eq!(errors[0].span.line, 2);
+        assert!(matches!(&itself.unwrap_err()[0].kind, ErrorKind::IncludeCycle(path) if path.ends_with("main.s")));
+    }
+
+    #[test]
+    fn incbin_packs_bytes_into_words() {
+        let dir = directory("incbin");
+        fs::write(dir.join("lib/data.bin"), [1, 2, 3]).unwrap();
+        let big = assemble_file(&dir, ".incbin \"data.bin\"");
+        let little = assemble_file(&dir, ".incbin \"data.bin\", little\ndat 4");
+        let missing = assemble_file(&dir, ".incbin \"other.bin\"");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(big.unwrap(), [0x0102, 0x0300]);
+        assert_eq!(little.unwrap(), [0x0201, 0x0003, 4]);
+        assert_eq!(missing.unwrap_err()[0].kind, ErrorKind::FileNotFound(String::from("other.bin")));
+    }
+
+    #[test]
+    fn every_expansion_has_its_own_labels() {
+        let source = "
+            .macro wait(n)
+            loop: sub n, 1
+            .again: ifn n, 0
+                set pc, .again
+                set pc, loop
+            .endmacro
+            main: wait(x)
+            wait(y)
+        ";
+        let expanded = "
+            main:
+            one: sub x, 1
+            .again: ifn x, 0
+                set pc, .again
+                set pc, one
+            two: sub y, 1
+            .again: ifn y, 0
+                set pc, .again
+                set pc, two
+        ";
+        assert_eq!(assemble(source).unwrap(), assemble(expanded).unwrap());
+        // Body labels aren't visible outside of the expansion
+        let errors = assemble(".macro m()\nhere: dat 0\n.endmacro\nm()\nset pc, here").unwrap_err();
+        assert_eq!(errors[0].kind, ErrorKind::UndefinedLabel(String::from("here")));
+        // Unless the caller names them
+        assert_eq!(assemble(".macro entry(name)\nname: dat 1\n.endmacro\nentry(start)\nset pc, start").unwrap(), [1, 0x8781]);
+    }
+
+    #[test]
+    fn recursive_expansion_notes_are_collapsed() {
+        let errors = assemble(".macro forever(n)\nforever(n)\n.endmacro\nforever(1)").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        let message = errors[0].to_string();
+        assert_eq!(message.matches("note:").count(), 2);
+        assert!(message.contains("t.s:2:1: note: in 63 nested expansions of this macro call"));
+        assert!(message.contains("t.s:4:1: note: in expansion of this macro call"));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::assembly::{AssembleError, ErrorKind, Expr, Located, Statement};
+
+// Local labels (`.loop`) belong to the global label before them and are
+// renamed to `global.loop`. Anonymous labels (`:`) are numbered `:0`,
+// `:1`, ... and `:-`/`:+` references are replaced with those names.
+pub fn resolve_scopes(program: &mut [Located], errors: &mut Vec<AssembleError>) {
+    let mut anonymous = vec![];
+    let mut scope: Option<String> = None;
+    for (i, located) in program.iter_mut().enumerate() {
+        if let Statement::Label(label) = &mut located.statement {
+            if label == ":" {
+                *label = format!(":{}", anonymous.len());
+                anonymous.push(i);
+            } else if label.starts_with('.') {
+                match &scope {
+                    Some(scope) => *label = format!("{}{}", scope, label),
+                    None => errors.push(AssembleError::new(located.span.clone(), ErrorKind::LocalOutsideScope(label.clone())))
+                }
+            } else {
+                scope = Some(label.clone());
+            }
+        }
+    }
+
+    let mut scope: Option<String> = None;
+    for (i, located) in program.iter_mut().enumerate() {
+        if let Statement::Label(label) = &located.statement {
+            if !label.starts_with(':') && !label.contains('.') {
+                scope = Some(label.clone());
+            }
+        }
+        for (index, expr) in located.statement.exprs_mut().into_iter().enumerate() {
+            let mut unresolved = vec![];
+            expr.labels_mut(&mut |label| {
+                if label.starts_with('.') {
+                    match &scope {
+                        Some(scope) => *label = format!("{}{}", scope, label),
+                        None => unresolved.push(ErrorKind::LocalOutsideScope(label.clone()))
+                    }
+                } else if let Some(steps) = label.strip_prefix(':') {
+                    // Anonymous labels defined before this statement
+                    let before = anonymous.iter().filter(|&&position| position < i).count();
+                    let index = if steps.starts_with('-') {
+                        before.checked_sub(steps.len())
+                    } else {
+                        Some(before + steps.len() - 1).filter(|&index| index < anonymous.len())
+                    };
+                    match index {
+                        Some(index) => *label = format!(":{}", index),
+                        None => unresolved.push(ErrorKind::UndefinedLabel(label.clone()))
+                    }
+                }
+            });
+            if unresolved.is_empty() {
+                continue;
+            }
+            // The expression can't be evaluated, it is replaced so that it
+            // isn't reported again as an undefined label
+            *expr = Expr::Number(0);
+            let span = located.operands.get(index).unwrap_or(&located.span);
+            errors.extend(unresolved.into_iter().map(|kind| AssembleError::new(span.clone(), kind)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembly::{assemble_source, ErrorKind, Options};
+
+    // Line, column and kind of each error
+    fn errors(source: &str) -> Vec<(usize, usize, ErrorKind)> {
+        assemble_source("t.s", source, &Options::default()).unwrap_err()
+            .into_iter()
+            .map(|error| (error.span.line, error.span.column, error.kind))
+            .collect()
+    }
+
+    #[test]
+    fn missing_anonymous_labels_are_reported_once() {
+        assert_eq!(errors("set pc, :+\n:\nset pc, :++"), [
+            (3, 9, ErrorKind::UndefinedLabel(String::from(":++")))
+        ]);
+        assert_eq!(errors("set pc, :-"), [(1, 9, ErrorKind::UndefinedLabel(String::from(":-")))]);
+    }
+
+    #[test]
+    fn locals_outside_of_a_scope_are_reported_once() {
+        assert_eq!(errors("set pc, .loop\nmain:\n.loop: set pc, .loop"), [
+            (1, 9, ErrorKind::LocalOutsideScope(String::from(".loop")))
+        ]);
+        assert_eq!(errors("dat 1, .end + 1"), [(1, 8, ErrorKind::LocalOutsideScope(String::from(".end")))]);
+    }
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+
+// Position of a statement or error in the source, line and column start at 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub snippet: String, // the whole source line
+    // Macro call the line was expanded from
+    pub expanded_from: Option<Box<Span>>
+}
+
+impl Span {
+    // Span of the byte `offset` in `source`
+    pub fn new(file: &str, source: &str, offset: usize) -> Span {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = source[offset..].find('\n').map(|i| offset + i).unwrap_or(source.len());
+        Span {
+            file: String::from(file),
+            line: before.matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            snippet: String::from(source[line_start..line_end].trim_end_matches('\r')),
+            expanded_from: None
+        }
+    }
+
+    // Source line with a caret under the column
+    pub fn fmt_snippet(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "    {}", self.snippet)?;
+        let indent: String = self.snippet.chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "    {}^", indent)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
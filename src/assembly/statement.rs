@@ -0,0 +1,205 @@
+use crate::assembly::{AssembleError, ErrorKind, Expr, Operand, Span};
+use crate::dcpu::{get_next_word, BasicOp, Command, SpecialOp, Value};
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Label(String),
+    Basic {
+        op: BasicOp,
+        b: Operand,
+        a: Operand
+    },
+    Special {
+        op: SpecialOp,
+        a: Operand
+    },
+    Data(Vec<Expr>),
+    // .define NAME expr, evaluated once labels are known
+    Define(String, Expr),
+    // Layout directives
+    Org(Expr),
+    Align(Expr),
+    Fill(Expr, Expr), // count, value
+    Reserve(Expr),
+    // Object file directives. In an image sections follow each other and
+    // .global does nothing.
+    Section(String),
+    Global(Vec<String>),
+    Extern(Vec<String>)
+}
+
+// Statement and where it was parsed from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Located {
+    pub statement: Statement,
+    pub span: Span,
+    // Where each expression of the statement starts, in exprs() order
+    pub operands: Vec<Span>
+}
+
+impl Located {
+    // Error at the first operand that fails with `kind`, or at the
+    // statement when no operand does on its own
+    pub fn error<F>(&self, kind: ErrorKind, eval: &F) -> AssembleError
+        where F: Fn(&Expr) -> Result<u16, ErrorKind> {
+        let span = self.statement.exprs().into_iter()
+            .zip(&self.operands)
+            .find(|(expr, _)| eval(expr).err().as_ref() == Some(&kind))
+            .map(|(_, span)| span)
+            .unwrap_or(&self.span);
+        AssembleError::new(span.clone(), kind)
+    }
+}
+
+impl Statement {
+    // Builds the command, evaluating next words with `eval`. With `short`
+    // a next word literal in a is encoded inline, the caller has to make
+    // sure it fits (see short_literal).
+    // Returns None for statements that don't produce code.
+    pub fn to_command<F>(&self, eval: &F, short: bool) -> Result<Option<Command>, ErrorKind>
+        where F: Fn(&Expr) -> Result<u16, ErrorKind> {
+        let a_value = |a: &Operand| match a {
+            Operand::NextWord(expr) if short => Ok(Value::Literal(eval(expr)?)),
+            a => a.to_value(eval)
+        };
+        Ok(match self {
+            Statement::Label(_) => None,
+            Statement::Data(_) => None,
+            Statement::Define(_, _) => None,
+            Statement::Org(_) | Statement::Align(_) | Statement::Fill(_, _) | Statement::Reserve(_) => None,
+            Statement::Section(_) | Statement::Global(_) | Statement::Extern(_) => None,
+            Statement::Basic { op, b, a } => Some(Command::Basic {
+                op: *op,
+                b: b.to_value(eval)?,
+                a: a_value(a)?
+            }),
+            Statement::Special { op, a } => Some(Command::Special {
+                op: *op,
+                a: a_value(a)?
+            })
+        })
+    }
+
+    // Whether a is a next word literal that fits the inline -1..30 range
+    pub fn short_literal(&self, labels: &HashMap<String, u16>) -> bool {
+        let a = match self {
+            Statement::Basic { op: _, b: _, a } => a,
+            Statement::Special { op: _, a } => a,
+            _ => return false
+        };
+        match a {
+            Operand::NextWord(expr) => match expr.eval(labels) {
+                Ok(value) => value == 0xffff || value <= 30,
+                Err(_) => false
+            },
+            _ => false
+        }
+    }
+
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Statement::Label(_) | Statement::Section(_) | Statement::Global(_) | Statement::Extern(_) => vec![],
+            Statement::Basic { op: _, b, a } => b.expr().into_iter().chain(a.expr()).collect(),
+            Statement::Special { op: _, a } => a.expr().into_iter().collect(),
+            Statement::Data(words) => words.iter().collect(),
+            Statement::Define(_, expr) | Statement::Org(expr) | Statement::Align(expr) | Statement::Reserve(expr) => vec![expr],
+            Statement::Fill(count, value) => vec![count, value]
+        }
+    }
+
+    pub fn exprs_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Statement::Label(_) | Statement::Section(_) | Statement::Global(_) | Statement::Extern(_) => vec![],
+            Statement::Basic { op: _, b, a } => b.expr_mut().into_iter().chain(a.expr_mut()).collect(),
+            Statement::Special { op: _, a } => a.expr_mut().into_iter().collect(),
+            Statement::Data(words) => words.iter_mut().collect(),
+            Statement::Define(_, expr) | Statement::Org(expr) | Statement::Align(expr) | Statement::Reserve(expr) => vec![expr],
+            Statement::Fill(count, value) => vec![count, value]
+        }
+    }
+
+    // Address after the statement when it starts at `address`, past 0xffff
+    // when the statement doesn't fit in memory
+    pub fn next_address<F>(&self, address: usize, short: bool, eval: &F) -> Result<usize, ErrorKind>
+        where F: Fn(&Expr) -> Result<u16, ErrorKind> {
+        Ok(match self {
+            Statement::Align(expr) => match eval(expr)? as usize {
+                0 => return Err(ErrorKind::DivisionByZero),
+                alignment => {
+                    let remainder = address % alignment;
+                    if remainder == 0 { address } else { address + alignment - remainder }
+                }
+            },
+            Statement::Fill(count, _) | Statement::Reserve(count) => address + eval(count)? as usize,
+            _ => address + self.size(short) as usize
+        })
+    }
+
+    // Words the statement emits. Alignment padding and reserved space
+    // aren't emitted.
+    pub fn encode<F>(&self, eval: &F, short: bool) -> Result<Vec<u16>, ErrorKind>
+        where F: Fn(&Expr) -> Result<u16, ErrorKind> {
+        match self {
+            Statement::Data(words) => return words.iter().map(eval).collect(),
+            Statement::Fill(count, value) => return Ok(vec![eval(value)?; eval(count)? as usize]),
+            _ => {}
+        }
+        let command = match self.to_command(eval, short)? {
+            Some(command) => command,
+            None => return Ok(vec![])
+        };
+        let mut words = vec![command.code()];
+        // a's next word comes first
+        match &command {
+            Command::Special { op: _, a } => words.extend(get_next_word(a)),
+            Command::Basic { op: _, b, a } => {
+                words.extend(get_next_word(a));
+                words.extend(get_next_word(b));
+            }
+        }
+        Ok(words)
+    }
+
+    // Number of words an instruction or dat takes, doesn't depend on
+    // label values
+    pub fn size(&self, short: bool) -> u16 {
+        if let Statement::Data(words) = self {
+            return words.len() as u16;
+        }
+        match self.to_command(&|_| Ok(0), short) {
+            Ok(Some(command)) => command.get_size(),
+            _ => 0
+        }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::Label(label) => write!(f, "{}:", label),
+            Statement::Define(name, expr) => write!(f, ".define {} {}", name, expr),
+            Statement::Org(expr) => write!(f, ".org {}", expr),
+            Statement::Align(expr) => write!(f, ".align {}", expr),
+            Statement::Fill(count, value) => write!(f, ".fill {}, {}", count, value),
+            Statement::Reserve(expr) => write!(f, ".reserve {}", expr),
+            Statement::Section(name) => write!(f, ".section {}", name),
+            Statement::Global(names) => write!(f, ".global {}", names.join(", ")),
+            Statement::Extern(names) => write!(f, ".extern {}", names.join(", ")),
+            Statement::Basic { op, b, a } => write!(f, "{} {}, {}", op, b, a),
+            Statement::Special { op, a } => write!(f, "{} {}", op, a),
+            Statement::Data(words) => {
+                write!(f, "dat ")?;
+                for (i, word) in words.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", word)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
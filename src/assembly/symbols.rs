@@ -0,0 +1,62 @@
+use crate::assembly::{layout, AssembleError, Layout, Located, Options, Statement};
+use crate::dcpu::{SourceLine, SymbolMap};
+
+// Symbol map of the program: the address of every label and the source
+// line of every instruction. Defines are constants rather than addresses
+// and anonymous labels have no usable name, so both are left out.
+pub fn symbols(program: &[Located], options: &Options) -> Result<SymbolMap, Vec<AssembleError>> {
+    let Layout { labels, addresses, .. } = layout(program, options)?;
+    let mut symbols = SymbolMap::new();
+    for (located, address) in program.iter().zip(addresses) {
+        match &located.statement {
+            Statement::Label(label) if !label.starts_with(':') => {
+                symbols.labels.insert(label.clone(), labels[label]);
+            },
+            Statement::Basic { .. } | Statement::Special { .. } => {
+                let source = SourceLine { file: located.span.file.clone(), line: located.span.line };
+                symbols.lines.insert(address, source);
+            },
+            _ => {}
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembly::{parse_source, symbols, Options};
+    use crate::dcpu::{SourceLine, SymbolMap};
+
+    use std::fs;
+
+    const SOURCE: &str = "\
+.define size 3
+main:   set a, size
+.loop:  sub a, 1
+:       ifn a, 0
+            set pc, .loop
+data:   dat 1, 2
+";
+
+    #[test]
+    fn labels_and_instruction_lines_are_exported() {
+        let map = symbols(&parse_source("main.s", SOURCE).unwrap(), &Options::default()).unwrap();
+        let labels: Vec<(&str, u16)> = map.labels.iter().map(|(label, address)| (label.as_str(), *address)).collect();
+        assert_eq!(labels, [("data", 4), ("main", 0), ("main.loop", 1)]);
+        let lines: Vec<(u16, usize)> = map.lines.iter().map(|(address, source)| (*address, source.line)).collect();
+        assert_eq!(lines, [(0, 2), (1, 3), (2, 4), (3, 5)]);
+        assert_eq!(map.line_at(3), Some(&SourceLine { file: String::from("main.s"), line: 5 }));
+        assert_eq!(map.label_before(5), Some(("data", 1)));
+    }
+
+    #[test]
+    fn exported_maps_can_be_read_back() {
+        let map = symbols(&parse_source("my program.s", SOURCE).unwrap(), &Options::default()).unwrap();
+        assert_eq!(SymbolMap::parse(&map.to_string()).unwrap(), map);
+        let path = std::env::temp_dir().join(format!("dcpu16-symbols-{}.sym", std::process::id()));
+        map.save(&path).unwrap();
+        let opened = SymbolMap::open(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(opened.unwrap(), map);
+    }
+}
@@ -1,6 +1,7 @@
+use std::fmt;
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum BasicOp {
     SET,
     ADD,
@@ -137,12 +138,24 @@ impl BasicOp {
             BasicOp::STD => 2
         }
     }
+
+    pub fn is_conditional(&self) -> bool {
+        matches!(self,
+            BasicOp::IFB |
+            BasicOp::IFC |
+            BasicOp::IFE |
+            BasicOp::IFN |
+            BasicOp::IFG |
+            BasicOp::IFA |
+            BasicOp::IFL |
+            BasicOp::IFU)
+    }
 }
 
 impl FromStr for BasicOp {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
+        match s.to_lowercase().as_str() {
             "set" => Ok(BasicOp::SET),
             "add" => Ok(BasicOp::ADD),
             "sub" => Ok(BasicOp::SUB),
@@ -174,3 +187,37 @@ impl FromStr for BasicOp {
         }
     }
 }
+
+impl fmt::Display for BasicOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BasicOp::SET => "set",
+            BasicOp::ADD => "add",
+            BasicOp::SUB => "sub",
+            BasicOp::MUL => "mul",
+            BasicOp::MLI => "mli",
+            BasicOp::DIV => "div",
+            BasicOp::DVI => "dvi",
+            BasicOp::MOD => "mod",
+            BasicOp::MDI => "mdi",
+            BasicOp::AND => "and",
+            BasicOp::BOR => "bor",
+            BasicOp::XOR => "xor",
+            BasicOp::SHR => "shr",
+            BasicOp::ASR => "asr",
+            BasicOp::SHL => "shl",
+            BasicOp::IFB => "ifb",
+            BasicOp::IFC => "ifc",
+            BasicOp::IFE => "ife",
+            BasicOp::IFN => "ifn",
+            BasicOp::IFG => "ifg",
+            BasicOp::IFA => "ifa",
+            BasicOp::IFL => "ifl",
+            BasicOp::IFU => "ifu",
+            BasicOp::ADX => "adx",
+            BasicOp::SBX => "sbx",
+            BasicOp::STI => "sti",
+            BasicOp::STD => "std"
+        })
+    }
+}
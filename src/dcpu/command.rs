@@ -1,6 +1,6 @@
 use crate::dcpu::{Value, BasicOp, SpecialOp};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Basic {
         op: BasicOp,
@@ -29,14 +29,8 @@ impl Command {
             let special_op_code = (val & B_MASK) >> B_SHIFT;
             let a_code = (val & A_MASK) >> A_SHIFT;
 
-            let op = match SpecialOp::new(special_op_code) {
-                Some(op) => op,
-                None => return None
-            };
-            let a = match Value::new(a_code) {
-                Some(a) => a,
-                None => return None
-            };
+            let op = SpecialOp::new(special_op_code)?;
+            let a = Value::new(a_code)?;
 
             Some(Command::Special{
                 op,
@@ -46,18 +40,9 @@ impl Command {
             let b_code = (val & B_MASK) >> B_SHIFT;
             let a_code = (val & A_MASK) >> A_SHIFT;
 
-            let op = match BasicOp::new(op_code) {
-                Some(op) => op,
-                None => return None
-            };
-            let b = match Value::new(b_code) {
-                Some(b) => b,
-                None => return None
-            };
-            let a = match Value::new(a_code) {
-                Some(a) => a,
-                None => return None
-            };
+            let op = BasicOp::new(op_code)?;
+            let b = Value::new(b_code)?;
+            let a = Value::new(a_code)?;
 
             Some(Command::Basic{
                 op,
@@ -79,7 +64,6 @@ impl Command {
                 let op_code = 0x00;
                 let a_code = a.code();
                 let special_op_code = op.code();
-                dbg!(op_code, a_code, special_op_code);
                 (a_code << A_SHIFT) | (special_op_code << B_SHIFT) | op_code
             }
         }
@@ -108,11 +92,21 @@ impl Command {
         };
         command_size + next_words_size
     }
+
+    // Cycles the instruction takes, not counting failed conditionals
+    // skipping the next instruction and what hardware adds to HWI
+    pub fn cycles(&self) -> usize {
+        match self {
+            Command::Basic { op, b, a } => op.cycles() + b.cycles() + a.cycles(),
+            Command::Special { op, a } => op.cycles() + a.cycles()
+        }
+    }
 }
 
 pub fn get_next_word(value: &Value) -> Option<u16> {
     match value {
         Value::IndexReg(_, word) => Some(*word),
+        Value::PICK(word) => Some(*word),
         Value::DerefNextWord(word) => Some(*word),
         Value::NextWord(word) => Some(*word),
         _ => None
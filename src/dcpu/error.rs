@@ -0,0 +1,39 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmulatorError {
+    // The word at `address` has no valid opcode
+    InvalidOpcode { address: u16, word: u16 },
+    // The opcode is valid but one of the operands isn't
+    InvalidOperand { address: u16, word: u16 },
+    // More than 256 interrupts were queued, the DCPU catches fire
+    OnFire,
+    // A device failed while handling HWI
+    HardwareFault { index: u16, message: String },
+    // The DCPU stopped after a previous error
+    Halted
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::InvalidOpcode { address, word } => {
+                write!(f, "invalid opcode {:#06x} at {:#06x}", word, address)
+            },
+            EmulatorError::InvalidOperand { address, word } => {
+                write!(f, "invalid operand in {:#06x} at {:#06x}", word, address)
+            },
+            EmulatorError::OnFire => {
+                write!(f, "interrupt queue overflow, the DCPU is on fire")
+            },
+            EmulatorError::HardwareFault { index, message } => {
+                write!(f, "hardware {} fault: {}", index, message)
+            },
+            EmulatorError::Halted => {
+                write!(f, "the DCPU is halted")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
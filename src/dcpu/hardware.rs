@@ -0,0 +1,42 @@
+use crate::dcpu::DCPU16;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// A device attached to the DCPU-16 hardware bus.
+pub trait Hardware {
+    fn id(&self) -> u32;
+    fn version(&self) -> u16;
+    fn manufacturer(&self) -> u32;
+
+    // Handles HWI, returns the number of cycles the device took on top
+    // of the HWI instruction itself, or a description of a device fault.
+    fn interrupt(&mut self, cpu: &mut DCPU16) -> Result<usize, String>;
+
+    // Called after every instruction with the number of cycles it took.
+    fn tick(&mut self, _cpu: &mut DCPU16, _cycles: usize) {}
+}
+
+// Lets the caller keep a handle to a device after attaching it,
+// e.g. to look at a monitor framebuffer or feed keys into a keyboard.
+impl<T: Hardware> Hardware for Rc<RefCell<T>> {
+    fn id(&self) -> u32 {
+        self.borrow().id()
+    }
+
+    fn version(&self) -> u16 {
+        self.borrow().version()
+    }
+
+    fn manufacturer(&self) -> u32 {
+        self.borrow().manufacturer()
+    }
+
+    fn interrupt(&mut self, cpu: &mut DCPU16) -> Result<usize, String> {
+        self.borrow_mut().interrupt(cpu)
+    }
+
+    fn tick(&mut self, cpu: &mut DCPU16, cycles: usize) {
+        self.borrow_mut().tick(cpu, cycles)
+    }
+}
@@ -6,12 +6,18 @@ mod value;
 mod basic_op;
 mod special_op;
 mod command;
+mod error;
+mod hardware;
+mod symbols;
 
 pub use register::*;
 pub use value::*;
 pub use basic_op::*;
 pub use special_op::*;
 pub use command::*;
+pub use error::*;
+pub use hardware::*;
+pub use symbols::*;
 
 use either::{Either};
 use enum_map::{EnumMap, enum_map};
@@ -24,8 +30,28 @@ pub struct DCPU16 {
     ex: u16,
     ia: u16,
     interrupt_queueing: bool,
-    int_queue: VecDeque<(u16, u16)>,
-    mem: [u16; 0x10000] // 128 KB of RAM
+    int_queue: VecDeque<u16>,
+    pub queue_overflow: QueueOverflow,
+    on_fire: bool,
+    fire_seed: u64,
+    hardware: Vec<Box<dyn Hardware>>,
+    pub cycles: usize, // cycles executed since power on
+    halted: bool,
+    pub mem: [u16; 0x10000] // 128 KB of RAM
+}
+
+// What happens when more than MAX_INT_QUEUE_SIZE interrupts are queued
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum QueueOverflow {
+    Halt, // stop with EmulatorError::OnFire
+    Reset, // reset the DCPU, memory and hardware are kept
+    CatchFire // keep running while memory gets randomly corrupted
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StepReport {
+    pub pc: u16, // PC after the instruction
+    pub cycles: usize // cycles the instruction took
 }
 
 impl Default for DCPU16 {
@@ -36,6 +62,9 @@ impl Default for DCPU16 {
 
 const MAX_INT_QUEUE_SIZE: usize = 256;
 
+// Nominal clock speed, devices use it to convert cycles to time
+pub const CPU_FREQUENCY: usize = 100_000;
+
 impl DCPU16 {
     pub fn new() -> DCPU16 {
         DCPU16{
@@ -50,11 +79,17 @@ impl DCPU16 {
                 Register::J => 0x0000,
             },
             pc: 0x0000,
-            sp: 0xFFFF,
+            sp: 0x0000,
             ex: 0x0000,
             ia: 0x0000,
             interrupt_queueing: false,
             int_queue: VecDeque::with_capacity(MAX_INT_QUEUE_SIZE),
+            queue_overflow: QueueOverflow::Halt,
+            on_fire: false,
+            fire_seed: 0x2545_f491_4f6c_dd1d,
+            hardware: vec![],
+            cycles: 0,
+            halted: false,
             mem: [0x0000; 0x10000]
         }
     }
@@ -63,372 +98,562 @@ impl DCPU16 {
         self.mem = rom;
     }
 
-    pub fn step(&mut self) -> Result<u16, &'static str> {
-        let cmd_code = self.mem[self.pc as usize];
-        self.pc += 1;
+    // Resets the DCPU to its power on state, memory and attached hardware
+    // are left as they are.
+    pub fn reset(&mut self) {
+        for (_, value) in self.reg.iter_mut() {
+            *value = 0x0000;
+        }
+        self.pc = 0x0000;
+        self.sp = 0x0000;
+        self.ex = 0x0000;
+        self.ia = 0x0000;
+        self.interrupt_queueing = false;
+        self.int_queue.clear();
+        self.on_fire = false;
+        self.halted = false;
+    }
+
+    // Attaches a device to the hardware bus, returns its hardware index.
+    pub fn attach(&mut self, device: Box<dyn Hardware>) -> u16 {
+        self.hardware.push(device);
+        (self.hardware.len() - 1) as u16
+    }
+
+    pub fn hardware_count(&self) -> u16 {
+        self.hardware.len() as u16
+    }
+
+    // Devices get a mutable reference to the DCPU, so the device list is
+    // moved out of self for the duration of the call. HWI to an empty slot
+    // does nothing, like HWQ which reports zeroes for it.
+    fn interrupt_hardware(&mut self, index: u16) -> Result<usize, EmulatorError> {
+        let mut hardware = std::mem::take(&mut self.hardware);
+        let result = match hardware.get_mut(index as usize) {
+            Some(device) => device.interrupt(self),
+            None => Ok(0)
+        };
+        self.hardware = hardware;
+        result.map_err(|message| EmulatorError::HardwareFault { index, message })
+    }
+
+    fn tick_hardware(&mut self, cycles: usize) {
+        let mut hardware = std::mem::take(&mut self.hardware);
+        for device in hardware.iter_mut() {
+            device.tick(self, cycles);
+        }
+        self.hardware = hardware;
+    }
+
+    // Queues an interrupt, it is triggered before the next instruction
+    // unless interrupt queueing is on. Interrupts are ignored while IA is 0.
+    pub fn interrupt(&mut self, message: u16) {
+        if self.ia != 0 {
+            self.int_queue.push_back(message);
+        }
+    }
+
+    pub fn queued_interrupts(&self) -> usize {
+        self.int_queue.len()
+    }
+
+    pub fn on_fire(&self) -> bool {
+        self.on_fire
+    }
+
+    // Flips a random bit somewhere in memory (xorshift64*)
+    fn burn(&mut self) {
+        self.fire_seed ^= self.fire_seed >> 12;
+        self.fire_seed ^= self.fire_seed << 25;
+        self.fire_seed ^= self.fire_seed >> 27;
+        let random = self.fire_seed.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        let address = (random >> 32) as u16;
+        let bit = (random >> 16) & 0xf;
+        self.mem[address as usize] ^= 1 << bit;
+    }
+
+    fn trigger_interrupt(&mut self, message: u16) {
+        self.interrupt_queueing = true;
+        self.push(self.pc);
+        self.push(self.reg[Register::A]);
+        self.pc = self.ia;
+        self.reg[Register::A] = message;
+    }
+
+    // PUSH / [--SP], returns the address of the new top of the stack
+    fn push_address(&mut self) -> u16 {
+        self.sp = self.sp.wrapping_sub(1);
+        self.sp
+    }
+
+    // POP / [SP++], returns the address of the popped word
+    fn pop_address(&mut self) -> u16 {
+        let address = self.sp;
+        self.sp = self.sp.wrapping_add(1);
+        address
+    }
+
+    pub fn push(&mut self, value: u16) {
+        let address = self.push_address();
+        self.mem[address as usize] = value;
+    }
+
+    pub fn pop(&mut self) -> u16 {
+        let address = self.pop_address();
+        self.mem[address as usize]
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn decode(&self, address: u16) -> Result<Command, EmulatorError> {
+        let word = self.mem[address as usize];
+        match Command::new(word) {
+            Some(cmd) => Ok(cmd),
+            None => {
+                let op_code = word & 0x1f;
+                let valid_op = if op_code == 0 {
+                    SpecialOp::new((word >> 5) & 0x1f).is_some()
+                } else {
+                    BasicOp::new(op_code).is_some()
+                };
+                if valid_op {
+                    Err(EmulatorError::InvalidOperand { address, word })
+                } else {
+                    Err(EmulatorError::InvalidOpcode { address, word })
+                }
+            }
+        }
+    }
+
+    // Skips the instruction at PC, conditional instructions are skipped
+    // together with the instruction that follows them. Every skipped
+    // instruction costs one cycle.
+    fn skip(&mut self) -> usize {
         let mut cycles = 0;
-        match Command::new(cmd_code) {
-            Some(cmd) => match cmd {
-                Command::Basic { op, b, a } => {
-                    cycles += op.cycles() + b.cycles() + a.cycles();
-                    // Get a copy of immutable operand A
-                    let a = self.value(a);
-                    // old_ex is copied here to prevent use of borrowed value error
-                    let old_ex = self.ex;
-                    // Get a mutable reference to mutable operand B
-                    let b = self.mut_value(&b);
-                    match op {
-                        BasicOp::SET => {
-                            if let Either::Right(b) = b {
-                                *b = a;
-                            }
-                        },
-                        BasicOp::ADD => {
-                            if let Either::Right(b) = b {
-                                let (result, overflow) = b.overflowing_add(a);
-                                *b = result;
-                                self.ex = if overflow { 0x0001 } else { 0x0000 };
-                            }
-                        },
-                        BasicOp::SUB => {
-                            if let Either::Right(b) = b {
-                                let (result, underflow) = b.overflowing_sub(a);
-                                *b = result;
-                                self.ex = if underflow { 0xffff } else { 0x0000 };
-                            }
-                        },
-                        BasicOp::MUL => {
-                            if let Either::Right(b) = b {
-                                let b32 = *b as u32;
-                                let a32 = a as u32;
-                                let result = b32 * a32;
-                                *b = (result & 0xffff) as u16;
-                                self.ex = ((result >> 16) & 0xffff) as u16;
-                            }
-                        },
-                        BasicOp::MLI => {
-                            if let Either::Right(b) = b {
-                                let b32 = *b as i32;
-                                let a32 = a as i32;
-                                let result = b32 * a32;
-                                *b = (result & 0xffff) as u16;
-                                self.ex = ((result >> 16) & 0xffff) as u16;
-                            }
-                        },
-                        BasicOp::DIV => {
-                            if let Either::Right(b) = b {
-                                if a == 0 {
-                                    *b = 0;
-                                    self.ex = 0;
-                                } else {
-                                    *b = b.wrapping_div(a);
-                                    let b32 = *b as u32;
-                                    let a32 = a as u32;
-                                    self.ex = (((b32 << 16) / a32) & 0xffff) as u16;
-                                }
-                            }
-                        },
-                        BasicOp::DVI => {
-                            if let Either::Right(b) = b {
-                                if a == 0 {
-                                    *b = 0;
-                                    self.ex = 0;
-                                } else {
-                                    let result = (*b as i16).wrapping_div(a as i16);
-                                    *b = result as u16;
-                                    let b32 = *b as i32;
-                                    let a32 = a as i32;
-                                    self.ex = (((b32 << 16) / a32) & 0xffff) as u16;
-                                }
-                            }
-                        },
-                        BasicOp::MOD => {
-                            if let Either::Right(b) = b {
-                                if a == 0 {
-                                    *b = 0;
-                                } else {
-                                    *b %= a;
-                                }
-                            }
-                        },
-                        BasicOp::MDI => {
-                            if let Either::Right(b) = b {
-                                if a == 0 {
-                                    *b = 0;
-                                } else {
-                                    let result = *b as i16 % a as i16;
-                                    *b = result as u16;
-                                }
-                            }
-                        },
-                        BasicOp::AND => {
-                            if let Either::Right(b) = b {
-                                *b &= a;
-                            }
-                        },
-                        BasicOp::BOR => {
-                            if let Either::Right(b) = b {
-                                *b |= a;
-                            }
-                        },
-                        BasicOp::XOR => {
-                            if let Either::Right(b) = b {
-                                *b ^= a;
-                            }
-                        },
-                        BasicOp::SHR => {
-                            if let Either::Right(b) = b {
+        // Bounded so that memory filled with conditionals can't hang the DCPU
+        while cycles <= 0xffff {
+            // Sizes come from the raw fields, skipped words don't have to be
+            // valid instructions
+            let word = self.mem[self.pc as usize];
+            let op_code = word & 0x1f;
+            let mut size = 1 + Value::has_next_word(word >> 10) as u16;
+            if op_code != 0 {
+                size += Value::has_next_word((word >> 5) & 0x1f) as u16;
+            }
+            self.pc = self.pc.wrapping_add(size);
+            cycles += 1;
+            match BasicOp::new(op_code) {
+                Some(op) if op_code != 0 && op.is_conditional() => {},
+                _ => break
+            }
+        }
+        cycles
+    }
+
+    // Executes instructions until at least `budget` cycles have passed,
+    // returns the number of cycles actually executed.
+    pub fn run_cycles(&mut self, budget: usize) -> Result<usize, EmulatorError> {
+        let mut executed = 0;
+        while executed < budget {
+            executed += self.step()?.cycles;
+        }
+        Ok(executed)
+    }
+
+    // Any error halts the DCPU, after that step only returns Halted.
+    pub fn step(&mut self) -> Result<StepReport, EmulatorError> {
+        if self.halted {
+            return Err(EmulatorError::Halted);
+        }
+        let result = self.execute();
+        if result.is_err() {
+            self.halted = true;
+        }
+        result
+    }
+
+    fn execute(&mut self) -> Result<StepReport, EmulatorError> {
+        if self.int_queue.len() > MAX_INT_QUEUE_SIZE {
+            match self.queue_overflow {
+                QueueOverflow::Halt => return Err(EmulatorError::OnFire),
+                QueueOverflow::Reset => self.reset(),
+                QueueOverflow::CatchFire => {
+                    self.on_fire = true;
+                    self.int_queue.truncate(MAX_INT_QUEUE_SIZE);
+                }
+            }
+        }
+        if self.on_fire {
+            self.burn();
+        }
+        // At most one interrupt is triggered between instructions
+        if !self.interrupt_queueing {
+            if let Some(message) = self.int_queue.pop_front() {
+                // IA could have been cleared after the interrupt was queued
+                if self.ia != 0 {
+                    self.trigger_interrupt(message);
+                }
+            }
+        }
+        let cmd = self.decode(self.pc)?;
+        self.pc = self.pc.wrapping_add(1);
+        let mut cycles = cmd.cycles();
+        let pc = match cmd {
+            Command::Basic { op, b, a } => {
+                // Get a copy of immutable operand A
+                let a = self.value(a);
+                // old_ex is copied here to prevent use of borrowed value error
+                let old_ex = self.ex;
+                // Get a mutable reference to mutable operand B
+                let b = self.mut_value(&b);
+                match op {
+                    BasicOp::SET => {
+                        if let Either::Right(b) = b {
+                            *b = a;
+                        }
+                    },
+                    BasicOp::ADD => {
+                        if let Either::Right(b) = b {
+                            let (result, overflow) = b.overflowing_add(a);
+                            *b = result;
+                            self.ex = if overflow { 0x0001 } else { 0x0000 };
+                        }
+                    },
+                    BasicOp::SUB => {
+                        if let Either::Right(b) = b {
+                            let (result, underflow) = b.overflowing_sub(a);
+                            *b = result;
+                            self.ex = if underflow { 0xffff } else { 0x0000 };
+                        }
+                    },
+                    BasicOp::MUL => {
+                        if let Either::Right(b) = b {
+                            let b32 = *b as u32;
+                            let a32 = a as u32;
+                            let result = b32 * a32;
+                            *b = (result & 0xffff) as u16;
+                            self.ex = ((result >> 16) & 0xffff) as u16;
+                        }
+                    },
+                    BasicOp::MLI => {
+                        if let Either::Right(b) = b {
+                            let b32 = *b as i16 as i32;
+                            let a32 = a as i16 as i32;
+                            let result = b32 * a32;
+                            *b = (result & 0xffff) as u16;
+                            self.ex = ((result >> 16) & 0xffff) as u16;
+                        }
+                    },
+                    BasicOp::DIV => {
+                        if let Either::Right(b) = b {
+                            if a == 0 {
+                                *b = 0;
+                                self.ex = 0;
+                            } else {
+                                *b = b.wrapping_div(a);
                                 let b32 = *b as u32;
                                 let a32 = a as u32;
-                                *b >>= a;
-                                self.ex = (((b32 << 16) >> a32) & 0xffff) as u16;
+                                self.ex = (((b32 << 16) / a32) & 0xffff) as u16;
                             }
-                        },
-                        BasicOp::ASR => {
-                            if let Either::Right(b) = b {
+                        }
+                    },
+                    BasicOp::DVI => {
+                        if let Either::Right(b) = b {
+                            if a == 0 {
+                                *b = 0;
+                                self.ex = 0;
+                            } else {
+                                let result = (*b as i16).wrapping_div(a as i16);
+                                *b = result as u16;
                                 let b32 = *b as i32;
                                 let a32 = a as i32;
-                                let result = *b as i16 >> a as i16;
-                                *b = result as u16;
-                                self.ex = (((b32 << 16) >> a32) & 0xffff) as u16;
-                            }
-                        },
-                        BasicOp::SHL => {
-                            if let Either::Right(b) = b {
-                                let b32 = *b as u32;
-                                let a32 = a as u32;
-                                *b <<= a;
-                                self.ex = (((b32 << a32) >> 16) & 0xffff) as u16;
+                                self.ex = (((b32 << 16) / a32) & 0xffff) as u16;
                             }
-                        },
-                        BasicOp::IFB => {
-                            let b = match b {
-                                Either::Right(b) => *b,
-                                Either::Left(b) => b
-                            };
-                            if b & a != 0x0000 {
-                                // Do nothing
-                            } else {
-                                // Skip next instruction
-                                let cmd = Command::new(self.mem[(self.pc + 1) as usize]).unwrap();
-                                let steps = cmd.get_size();
-                                self.pc += steps;
-                            }
-                        },
-                        BasicOp::IFC => {
-                            let b = match b {
-                                Either::Right(b) => *b,
-                                Either::Left(b) => b
-                            };
-                            if b & a == 0x0000 {
-                                // Do nothing
-                            } else {
-                                // Skip next instruction
-                                let cmd = Command::new(self.mem[(self.pc + 1) as usize]).unwrap();
-                                let steps = cmd.get_size();
-                                self.pc += steps;
-                            }
-                        },
-                        BasicOp::IFE => {
-                            let b = match b {
-                                Either::Right(b) => *b,
-                                Either::Left(b) => b
-                            };
-                            if b == a {
-                                // Do nothing
-                            } else {
-                                // Skip next instruction
-                                let cmd = Command::new(self.mem[(self.pc + 1) as usize]).unwrap();
-                                let steps = cmd.get_size();
-                                self.pc += steps;
-                            }
-                        },
-                        BasicOp::IFN => {
-                            let b = match b {
-                                Either::Right(b) => *b,
-                                Either::Left(b) => b
-                            };
-                            if b != a {
-                                // Do nothing
-                            } else {
-                                // Skip next instruction
-                                let cmd = Command::new(self.mem[(self.pc + 1) as usize]).unwrap();
-                                let steps = cmd.get_size();
-                                self.pc += steps;
-                            }
-                        },
-                        BasicOp::IFG => {
-                            let b = match b {
-                                Either::Right(b) => *b,
-                                Either::Left(b) => b
-                            };
-                            if b > a {
-                                // Do nothing
-                            } else {
-                                // Skip next instruction
-                                let cmd = Command::new(self.mem[(self.pc + 1) as usize]).unwrap();
-                                let steps = cmd.get_size();
-                                self.pc += steps;
-                            }
-                        },
-                        BasicOp::IFA => {
-                            let b = match b {
-                                Either::Right(b) => *b,
-                                Either::Left(b) => b
-                            };
-                            if b as i16 > a as i16 {
-                                // Do nothing
-                            } else {
-                                // Skip next instruction
-                                let cmd = Command::new(self.mem[(self.pc + 1) as usize]).unwrap();
-                                let steps = cmd.get_size();
-                                self.pc += steps;
-                            }
-                        },
-                        BasicOp::IFL => {
-                            let b = match b {
-                                Either::Right(b) => *b,
-                                Either::Left(b) => b
-                            };
-                            if b < a {
-                                // Do nothing
+                        }
+                    },
+                    BasicOp::MOD => {
+                        if let Either::Right(b) = b {
+                            if a == 0 {
+                                *b = 0;
                             } else {
-                                // Skip next instruction
-                                let cmd = Command::new(self.mem[self.pc as usize]).unwrap();
-                                let steps = cmd.get_size();
-                                self.pc += steps;
+                                *b %= a;
                             }
-                        },
-                        BasicOp::IFU => {
-                            let b = match b {
-                                Either::Right(b) => *b,
-                                Either::Left(b) => b
-                            };
-                            if (b as i16) < (a as i16) {
-                                // Do nothing
+                        }
+                    },
+                    BasicOp::MDI => {
+                        if let Either::Right(b) = b {
+                            if a == 0 {
+                                *b = 0;
                             } else {
-                                // Skip next instruction
-                                let cmd = Command::new(self.mem[(self.pc + 1) as usize]).unwrap();
-                                let steps = cmd.get_size();
-                                self.pc += steps;
-                            }
-                        },
-
-                        BasicOp::ADX => {
-                            if let Either::Right(b) = b {
-                                let (result, overflow_1) = b.overflowing_add(a);
-                                let (result, overflow_2) = result.overflowing_add(old_ex);
-                                let overflow = overflow_1 || overflow_2;
-                                *b = result;
-                                self.ex = if overflow { 0x0001 } else { 0x0000 };
-                            }
-                        },
-                        BasicOp::SBX => {
-                            if let Either::Right(b) = b {
-                                let (result, underflow) = b.overflowing_sub(a);
-                                let (result, overflow) = result.overflowing_add(old_ex);
-                                let trouble = underflow || overflow;
-                                *b = result;
-                                self.ex = if trouble { 0xffff } else { 0x0000 };
-                            }
-                        },
-                        BasicOp::STI => {
-                            if let Either::Right(b) = b {
-                                *b = a;
-                                self.reg[Register::I] += 1;
-                                self.reg[Register::J] += 1;
-                            }
-                        },
-                        BasicOp::STD => {
-                            if let Either::Right(b) = b {
-                                *b = a;
-                                self.reg[Register::I] -= 1;
-                                self.reg[Register::J] -= 1;
+                                let result = (*b as i16).wrapping_rem(a as i16);
+                                *b = result as u16;
                             }
                         }
-                    }
-                    Ok(self.pc)
-                },
-                Command::Special { op, a } => {
-                    let old_ia = self.ia;
-                    let a = self.mut_value(&a);
-                    match op {
-                        SpecialOp::JSR => {
-                            let a = match a {
-                                Either::Right(a) => *a,
-                                Either::Left(a) => a
-                            };
-                            self.mem[self.sp as usize] = self.pc;
-                            self.sp -= 1;
-                            self.pc = a;
-                        },
-                        SpecialOp::INT => {
-                            let a = match a {
-                                Either::Right(a) => *a,
-                                Either::Left(a) => a
-                            };
-                            if self.ia != 0 {
-                                if !self.interrupt_queueing {
-                                    self.interrupt_queueing = true;
-                                    self.sp += 1;
-                                    self.mem[self.sp as usize] = self.pc;
-                                    self.sp += 1;
-                                    self.mem[self.sp as usize] = self.reg[Register::A];
-                                    self.pc = self.ia;
-                                    self.reg[Register::A] = a;
-                                } else {
-                                    // It is not clear if it is possible to cause
-                                    // software interrupts to queue.
-                                    self.int_queue.push_back((self.pc, a));
-                                }
-                            }
-                        },
-                        SpecialOp::IAG => {
-                            if let Either::Right(a) = a {
-                                *a = old_ia;
-                            }
-                        },
-                        SpecialOp::IAS => {
-                            let a = match a {
-                                Either::Right(a) => *a,
-                                Either::Left(a) => a
-                            };
-                            self.ia = a;
-                        },
-                        SpecialOp::RFI => {
-                            self.reg[Register::A] = self.mem[self.sp as usize];
-                            self.sp -= 1;
-                            self.pc = self.mem[self.sp as usize];
-                            self.sp -= 1;
-                            self.interrupt_queueing = false;
-                        },
-                        SpecialOp::IAQ => {
-                            let a = match a {
-                                Either::Right(a) => *a,
-                                Either::Left(a) => a
-                            };
-                            self.interrupt_queueing = a != 0;
-                        },
-                        SpecialOp::HWN => {
-                            unimplemented!();
-                        },
-                        SpecialOp::HWQ => {
-                            unimplemented!();
-                        },
-                        SpecialOp::HWI => {
-                            unimplemented!();
+                    },
+                    BasicOp::AND => {
+                        if let Either::Right(b) = b {
+                            *b &= a;
+                        }
+                    },
+                    BasicOp::BOR => {
+                        if let Either::Right(b) = b {
+                            *b |= a;
+                        }
+                    },
+                    BasicOp::XOR => {
+                        if let Either::Right(b) = b {
+                            *b ^= a;
+                        }
+                    },
+                    BasicOp::SHR => {
+                        if let Either::Right(b) = b {
+                            let b32 = *b as u32;
+                            let a32 = a as u32;
+                            *b = b.checked_shr(a32).unwrap_or(0);
+                            self.ex = ((b32 << 16).checked_shr(a32).unwrap_or(0) & 0xffff) as u16;
+                        }
+                    },
+                    BasicOp::ASR => {
+                        if let Either::Right(b) = b {
+                            let b32 = *b as i16 as i32;
+                            let a32 = a.min(31) as i32;
+                            let result = (*b as i16) >> a.min(15);
+                            *b = result as u16;
+                            self.ex = (((b32 << 16) >> a32) & 0xffff) as u16;
+                        }
+                    },
+                    BasicOp::SHL => {
+                        if let Either::Right(b) = b {
+                            let b64 = *b as u64;
+                            let a32 = a as u32;
+                            *b = b.checked_shl(a32).unwrap_or(0);
+                            self.ex = ((b64.checked_shl(a32).unwrap_or(0) >> 16) & 0xffff) as u16;
+                        }
+                    },
+                    BasicOp::IFB => {
+                        let b = match b {
+                            Either::Right(b) => *b,
+                            Either::Left(b) => b
+                        };
+                        if b & a != 0x0000 {
+                            // Do nothing
+                        } else {
+                            // Skip next instruction
+                            cycles += self.skip();
+                        }
+                    },
+                    BasicOp::IFC => {
+                        let b = match b {
+                            Either::Right(b) => *b,
+                            Either::Left(b) => b
+                        };
+                        if b & a == 0x0000 {
+                            // Do nothing
+                        } else {
+                            // Skip next instruction
+                            cycles += self.skip();
+                        }
+                    },
+                    BasicOp::IFE => {
+                        let b = match b {
+                            Either::Right(b) => *b,
+                            Either::Left(b) => b
+                        };
+                        if b == a {
+                            // Do nothing
+                        } else {
+                            // Skip next instruction
+                            cycles += self.skip();
+                        }
+                    },
+                    BasicOp::IFN => {
+                        let b = match b {
+                            Either::Right(b) => *b,
+                            Either::Left(b) => b
+                        };
+                        if b != a {
+                            // Do nothing
+                        } else {
+                            // Skip next instruction
+                            cycles += self.skip();
+                        }
+                    },
+                    BasicOp::IFG => {
+                        let b = match b {
+                            Either::Right(b) => *b,
+                            Either::Left(b) => b
+                        };
+                        if b > a {
+                            // Do nothing
+                        } else {
+                            // Skip next instruction
+                            cycles += self.skip();
+                        }
+                    },
+                    BasicOp::IFA => {
+                        let b = match b {
+                            Either::Right(b) => *b,
+                            Either::Left(b) => b
+                        };
+                        if b as i16 > a as i16 {
+                            // Do nothing
+                        } else {
+                            // Skip next instruction
+                            cycles += self.skip();
+                        }
+                    },
+                    BasicOp::IFL => {
+                        let b = match b {
+                            Either::Right(b) => *b,
+                            Either::Left(b) => b
+                        };
+                        if b < a {
+                            // Do nothing
+                        } else {
+                            // Skip next instruction
+                            cycles += self.skip();
+                        }
+                    },
+                    BasicOp::IFU => {
+                        let b = match b {
+                            Either::Right(b) => *b,
+                            Either::Left(b) => b
+                        };
+                        if (b as i16) < (a as i16) {
+                            // Do nothing
+                        } else {
+                            // Skip next instruction
+                            cycles += self.skip();
+                        }
+                    },
+
+                    BasicOp::ADX => {
+                        if let Either::Right(b) = b {
+                            let (result, overflow_1) = b.overflowing_add(a);
+                            let (result, overflow_2) = result.overflowing_add(old_ex);
+                            let overflow = overflow_1 || overflow_2;
+                            *b = result;
+                            self.ex = if overflow { 0x0001 } else { 0x0000 };
+                        }
+                    },
+                    BasicOp::SBX => {
+                        if let Either::Right(b) = b {
+                            let (result, underflow) = b.overflowing_sub(a);
+                            let (result, overflow) = result.overflowing_add(old_ex);
+                            let trouble = underflow || overflow;
+                            *b = result;
+                            self.ex = if trouble { 0xffff } else { 0x0000 };
+                        }
+                    },
+                    BasicOp::STI => {
+                        if let Either::Right(b) = b {
+                            *b = a;
+                            self.reg[Register::I] = self.reg[Register::I].wrapping_add(1);
+                            self.reg[Register::J] = self.reg[Register::J].wrapping_add(1);
+                        }
+                    },
+                    BasicOp::STD => {
+                        if let Either::Right(b) = b {
+                            *b = a;
+                            self.reg[Register::I] = self.reg[Register::I].wrapping_sub(1);
+                            self.reg[Register::J] = self.reg[Register::J].wrapping_sub(1);
                         }
                     }
-                    Ok(self.pc)
                 }
+                self.pc
             },
-            None => Err("couldn't decode command")
-        }
+            Command::Special { op, a } => {
+                let old_ia = self.ia;
+                let hardware_count = self.hardware_count();
+                // STACK in a is always POP, even when a is written to (IAG, HWN)
+                let a = match a {
+                    Value::STACK => {
+                        let address = self.pop_address();
+                        Either::Right(&mut self.mem[address as usize])
+                    },
+                    a => self.mut_value(&a)
+                };
+                match op {
+                    SpecialOp::JSR => {
+                        let a = match a {
+                            Either::Right(a) => *a,
+                            Either::Left(a) => a
+                        };
+                        self.push(self.pc);
+                        self.pc = a;
+                    },
+                    SpecialOp::INT => {
+                        let a = match a {
+                            Either::Right(a) => *a,
+                            Either::Left(a) => a
+                        };
+                        self.interrupt(a);
+                    },
+                    SpecialOp::IAG => {
+                        if let Either::Right(a) = a {
+                            *a = old_ia;
+                        }
+                    },
+                    SpecialOp::IAS => {
+                        let a = match a {
+                            Either::Right(a) => *a,
+                            Either::Left(a) => a
+                        };
+                        self.ia = a;
+                    },
+                    SpecialOp::RFI => {
+                        self.reg[Register::A] = self.pop();
+                        self.pc = self.pop();
+                        self.interrupt_queueing = false;
+                    },
+                    SpecialOp::IAQ => {
+                        let a = match a {
+                            Either::Right(a) => *a,
+                            Either::Left(a) => a
+                        };
+                        self.interrupt_queueing = a != 0;
+                    },
+                    SpecialOp::HWN => {
+                        if let Either::Right(a) = a {
+                            *a = hardware_count;
+                        }
+                    },
+                    SpecialOp::HWQ => {
+                        let a = match a {
+                            Either::Right(a) => *a,
+                            Either::Left(a) => a
+                        };
+                        let (id, version, manufacturer) = match self.hardware.get(a as usize) {
+                            Some(device) => (device.id(), device.version(), device.manufacturer()),
+                            None => (0, 0, 0)
+                        };
+                        self.reg[Register::A] = (id & 0xffff) as u16;
+                        self.reg[Register::B] = (id >> 16) as u16;
+                        self.reg[Register::C] = version;
+                        self.reg[Register::X] = (manufacturer & 0xffff) as u16;
+                        self.reg[Register::Y] = (manufacturer >> 16) as u16;
+                    },
+                    SpecialOp::HWI => {
+                        let a = match a {
+                            Either::Right(a) => *a,
+                            Either::Left(a) => a
+                        };
+                        cycles += self.interrupt_hardware(a)?;
+                    }
+                }
+                self.pc
+            }
+        };
+        self.cycles += cycles;
+        self.tick_hardware(cycles);
+        Ok(StepReport { pc, cycles })
     }
 
     pub fn next_word(&mut self) -> u16 {
         let word = self.mem[self.pc as usize];
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         word
     }
 
@@ -441,19 +666,17 @@ impl DCPU16 {
                 self.mem[self.reg[reg] as usize]
             },
             Value::IndexReg(reg, _) => {
-                let address = self.reg[reg] + self.next_word();
+                let address = self.reg[reg].wrapping_add(self.next_word());
                 self.mem[address as usize]
             },
             Value::STACK => {
-                let pop = self.mem[self.sp as usize];
-                self.sp += 1;
-                pop
+                self.pop()
             },
             Value::PEEK => {
                 self.mem[self.sp as usize]
             },
-            Value::PICK => {
-                let address = self.sp + self.next_word();
+            Value::PICK(_) => {
+                let address = self.sp.wrapping_add(self.next_word());
                 self.mem[address as usize]
             },
             Value::SP => {
@@ -486,18 +709,18 @@ impl DCPU16 {
                 Either::Right(&mut self.mem[self.reg[*reg] as usize])
             },
             Value::IndexReg(reg, _) => {
-                let address = self.reg[*reg] + self.next_word();
+                let address = self.reg[*reg].wrapping_add(self.next_word());
                 Either::Right(&mut self.mem[address as usize])
             },
             Value::STACK => {
-                self.sp -= 1;
-                Either::Right(&mut self.mem[self.sp as usize])
+                let address = self.push_address();
+                Either::Right(&mut self.mem[address as usize])
             },
             Value::PEEK => {
                 Either::Right(&mut self.mem[self.sp as usize])
             },
-            Value::PICK => {
-                let address = self.sp + self.next_word();
+            Value::PICK(_) => {
+                let address = self.sp.wrapping_add(self.next_word());
                 Either::Right(&mut self.mem[address as usize])
             },
             Value::SP => {
@@ -515,7 +738,7 @@ impl DCPU16 {
             },
             Value::NextWord(_) => {
                 let result = &mut self.mem[self.pc as usize];
-                self.pc += 1;
+                self.pc = self.pc.wrapping_add(1);
                 Either::Right(result)
             },
             Value::Literal(literal) => {
@@ -524,3 +747,390 @@ impl DCPU16 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn boot(source: &str) -> DCPU16 {
+        let code = assembly::generate_code(assembly::parse(source).unwrap()).unwrap();
+        let mut cpu = DCPU16::new();
+        cpu.mem[..code.len()].copy_from_slice(&code);
+        cpu
+    }
+
+    // Device that counts HWIs and takes `cost` cycles for each
+    #[derive(Default)]
+    struct Probe {
+        interrupts: usize,
+        cost: usize
+    }
+
+    impl Hardware for Probe {
+        fn id(&self) -> u32 {
+            0x12345678
+        }
+
+        fn version(&self) -> u16 {
+            0x0042
+        }
+
+        fn manufacturer(&self) -> u32 {
+            0x9abcdef0
+        }
+
+        fn interrupt(&mut self, _cpu: &mut DCPU16) -> Result<usize, String> {
+            self.interrupts += 1;
+            Ok(self.cost)
+        }
+    }
+
+    #[test]
+    fn hwn_counts_attached_devices() {
+        let mut cpu = boot("hwn i");
+        cpu.attach(Box::new(Probe::default()));
+        cpu.attach(Box::new(Probe::default()));
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg[Register::I], 2);
+        assert_eq!(cpu.hardware_count(), 2);
+    }
+
+    #[test]
+    fn hwq_describes_a_device() {
+        let mut cpu = boot("
+            hwq 1
+            hwq 2
+        ");
+        cpu.attach(Box::new(Probe::default()));
+        cpu.attach(Box::new(Probe::default()));
+        cpu.step().unwrap();
+        // A+(B<<16) is the id, C the version, X+(Y<<16) the manufacturer
+        assert_eq!(cpu.reg[Register::A], 0x5678);
+        assert_eq!(cpu.reg[Register::B], 0x1234);
+        assert_eq!(cpu.reg[Register::C], 0x0042);
+        assert_eq!(cpu.reg[Register::X], 0xdef0);
+        assert_eq!(cpu.reg[Register::Y], 0x9abc);
+        // Nothing in slot 2
+        cpu.step().unwrap();
+        for register in [Register::A, Register::B, Register::C, Register::X, Register::Y].iter() {
+            assert_eq!(cpu.reg[*register], 0);
+        }
+    }
+
+    #[test]
+    fn hwi_interrupts_the_device_and_adds_its_cycles() {
+        let mut cpu = boot("hwi 0");
+        let probe = Rc::new(RefCell::new(Probe { interrupts: 0, cost: 10 }));
+        cpu.attach(Box::new(probe.clone()));
+        assert_eq!(cpu.step().unwrap().cycles, 14);
+        assert_eq!(probe.borrow().interrupts, 1);
+    }
+
+    #[test]
+    fn hwi_to_an_empty_slot_does_nothing() {
+        let mut cpu = boot("
+            hwi 3
+            set a, 1
+        ");
+        let report = cpu.step().unwrap();
+        // Just the instruction itself, 4 cycles and a short literal
+        assert_eq!(report.cycles, 4);
+        assert_eq!(cpu.step().unwrap().pc, 2);
+        assert_eq!(cpu.reg[Register::A], 1);
+    }
+
+    #[test]
+    fn skipping_data_words_is_not_an_error() {
+        let mut cpu = boot("
+            ife a, 1
+            dat 0
+            set a, 2
+        ");
+        assert_eq!(cpu.step().unwrap().pc, 2);
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg[Register::A], 2);
+    }
+
+    #[test]
+    fn chained_ifs_skip_over_data_words() {
+        let mut cpu = boot("
+            ife a, 1
+            ifn b, 0x1000
+            dat 0
+            set b, 3
+        ");
+        // 2 for IFE and one per skipped word: the IFN with its next word
+        // and the data word, which isn't a valid instruction
+        let report = cpu.step().unwrap();
+        assert_eq!(report.pc, 4);
+        assert_eq!(report.cycles, 4);
+        assert_eq!(cpu.step().unwrap().pc, 5);
+        assert_eq!(cpu.reg[Register::B], 3);
+    }
+
+    // Cycles the first instruction of `source` takes
+    fn cost(source: &str) -> usize {
+        boot(source).step().unwrap().cycles
+    }
+
+    #[test]
+    fn basic_op_costs() {
+        assert_eq!(cost("set a, 1"), 1);
+        assert_eq!(cost("add a, b"), 2);
+        assert_eq!(cost("div a, b"), 3);
+        assert_eq!(cost("adx a, b"), 3);
+        // Next words cost one more each, wherever they are
+        assert_eq!(cost("set a, 0x1000"), 2);
+        assert_eq!(cost("set [0x1000], a"), 2);
+        assert_eq!(cost("set [a + 1], 0x1000"), 3);
+        assert_eq!(cost("set [0x1000], [0x1001]"), 3);
+    }
+
+    #[test]
+    fn special_op_costs() {
+        assert_eq!(cost("jsr 0x1000"), 4);
+        assert_eq!(cost("jsr a"), 3);
+        assert_eq!(cost("int 1"), 4);
+        assert_eq!(cost("iag a"), 1);
+        assert_eq!(cost("ias 0"), 1);
+        assert_eq!(cost("rfi 0"), 3);
+        assert_eq!(cost("iaq 0"), 2);
+        assert_eq!(cost("hwn a"), 2);
+        assert_eq!(cost("hwq 0"), 4);
+    }
+
+    #[test]
+    fn failed_ifs_cost_one_per_skipped_instruction() {
+        assert_eq!(cost("ife a, 0"), 2);
+        assert_eq!(cost("ife a, 1\nset a, 1"), 3);
+        // The skipped instruction's size doesn't matter
+        assert_eq!(cost("ife a, 1\nset [0x1000], 0x2000"), 3);
+        assert_eq!(cost("ife a, 0x1000\nset a, 1"), 4);
+        // Chained conditionals are skipped with what follows them
+        assert_eq!(cost("ife a, 1\nifn a, 0\nifg a, 0\nset a, 1"), 5);
+    }
+
+    #[test]
+    fn run_cycles_stops_once_the_budget_is_spent() {
+        let mut cpu = boot("
+            loop:
+                add a, 1
+                set pc, loop
+        ");
+        // 2 and 1 cycles per instruction: 2, 3, 5, 6, 8, 9, 11
+        assert_eq!(cpu.run_cycles(10).unwrap(), 11);
+        assert_eq!(cpu.cycles, 11);
+        assert_eq!(cpu.reg[Register::A], 4);
+        assert_eq!(cpu.run_cycles(1).unwrap(), 1);
+        assert_eq!(cpu.cycles, 12);
+    }
+
+    #[test]
+    fn one_interrupt_is_triggered_per_instruction() {
+        let mut cpu = boot("
+                ias handler
+            wait:
+                set pc, wait
+            handler:
+                iaq 0
+                add [0x1000], a
+                rfi 0
+        ");
+        cpu.step().unwrap();
+        cpu.interrupt(1);
+        cpu.interrupt(2);
+        assert_eq!(cpu.queued_interrupts(), 2);
+        // The handler turns queueing off, so the second interrupt nests
+        cpu.step().unwrap();
+        assert_eq!(cpu.queued_interrupts(), 1);
+        cpu.step().unwrap();
+        assert_eq!(cpu.queued_interrupts(), 0);
+        cpu.run_cycles(30).unwrap();
+        assert_eq!(cpu.mem[0x1000], 3);
+    }
+
+    #[test]
+    fn iaq_holds_interrupts_back() {
+        let mut cpu = boot("
+                ias handler
+                iaq 1
+                int 7
+                set b, 6
+                iaq 0
+            wait:
+                set pc, wait
+            handler:
+                set [0x1000], a
+                rfi 0
+        ");
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.queued_interrupts(), 1);
+        assert_eq!(cpu.mem[0x1000], 0);
+        // IAQ 0, then the interrupt is triggered before the next instruction
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.queued_interrupts(), 0);
+        assert_eq!(cpu.mem[0x1000], 7);
+    }
+
+    #[test]
+    fn interrupts_are_dropped_while_ia_is_0() {
+        let mut cpu = boot("
+                iaq 1
+                ias handler
+                int 1
+                ias 0
+                iaq 0
+                set a, 1
+                dat 0
+            handler:
+                set b, 1
+                rfi 0
+        ");
+        cpu.interrupt(2);
+        assert_eq!(cpu.queued_interrupts(), 0);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        // Queued while IA was set, dropped once it was cleared
+        assert_eq!(cpu.queued_interrupts(), 1);
+        cpu.step().unwrap();
+        assert_eq!(cpu.queued_interrupts(), 0);
+        assert_eq!(cpu.reg[Register::A], 1);
+        assert_eq!(cpu.reg[Register::B], 0);
+    }
+
+    // DCPU with `count` interrupts queued and queueing on
+    fn flooded(count: usize, overflow: QueueOverflow) -> DCPU16 {
+        let mut cpu = boot("
+                ias handler
+                iaq 1
+            wait:
+                set pc, wait
+            handler:
+                rfi 0
+        ");
+        cpu.queue_overflow = overflow;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        for message in 0..count {
+            cpu.interrupt(message as u16 + 1);
+        }
+        cpu
+    }
+
+    #[test]
+    fn full_queue_is_fine() {
+        let mut cpu = flooded(MAX_INT_QUEUE_SIZE, QueueOverflow::Halt);
+        cpu.step().unwrap();
+        assert_eq!(cpu.queued_interrupts(), MAX_INT_QUEUE_SIZE);
+    }
+
+    #[test]
+    fn queue_overflow_can_halt() {
+        let mut cpu = flooded(MAX_INT_QUEUE_SIZE + 1, QueueOverflow::Halt);
+        assert!(matches!(cpu.step(), Err(EmulatorError::OnFire)));
+        assert!(cpu.halted());
+        assert!(matches!(cpu.step(), Err(EmulatorError::Halted)));
+    }
+
+    #[test]
+    fn queue_overflow_can_reset() {
+        let mut cpu = flooded(MAX_INT_QUEUE_SIZE + 1, QueueOverflow::Reset);
+        // Starts over from 0 with an empty queue, memory is kept
+        let report = cpu.step().unwrap();
+        assert_eq!(report.pc, 1);
+        assert_eq!(cpu.queued_interrupts(), 0);
+        assert!(!cpu.halted());
+    }
+
+    #[test]
+    fn queue_overflow_can_catch_fire() {
+        let mut cpu = flooded(MAX_INT_QUEUE_SIZE + 1, QueueOverflow::CatchFire);
+        let before = cpu.mem;
+        cpu.step().unwrap();
+        assert!(cpu.on_fire());
+        assert_eq!(cpu.queued_interrupts(), MAX_INT_QUEUE_SIZE);
+        // One bit flipped somewhere
+        let flipped: u32 = before.iter().zip(cpu.mem.iter()).map(|(a, b)| (a ^ b).count_ones()).sum();
+        assert_eq!(flipped, 1);
+    }
+
+    #[test]
+    fn jsr_returns_with_set_pc_pop() {
+        let mut cpu = boot("
+                jsr sub
+                set b, 2
+                dat 0
+            sub:
+                set a, 1
+                set pc, pop
+        ");
+        cpu.step().unwrap();
+        // Return address at [--SP], SP wraps to 0xffff
+        assert_eq!(cpu.sp, 0xffff);
+        assert_eq!(cpu.mem[0xffff], 1);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap().pc, 1);
+        assert_eq!(cpu.sp, 0);
+        cpu.step().unwrap();
+        assert_eq!((cpu.reg[Register::A], cpu.reg[Register::B]), (1, 2));
+    }
+
+    #[test]
+    fn push_and_pop_share_the_stack_with_jsr() {
+        let mut cpu = boot("
+                set push, 0x1234
+                jsr sub
+                set b, pop
+                dat 0
+            sub:
+                set a, peek
+                set c, [sp + 1]
+                set pc, pop
+        ");
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        // Return address on top of the pushed value
+        assert_eq!(cpu.reg[Register::A], 3);
+        assert_eq!(cpu.reg[Register::C], 0x1234);
+        assert_eq!(cpu.reg[Register::B], 0x1234);
+        assert_eq!(cpu.sp, 0);
+    }
+
+    #[test]
+    fn int_and_rfi_restore_pc_and_a() {
+        let mut cpu = boot("
+                ias handler
+                set a, 5
+                int 9
+                set b, a
+                dat 0
+            handler:
+                set c, a
+                set a, 0xbeef
+                rfi 0
+        ");
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        // Triggering pushes PC then A
+        cpu.step().unwrap();
+        assert_eq!(cpu.sp, 0xfffe);
+        assert_eq!(cpu.mem[0xffff], 3);
+        assert_eq!(cpu.mem[0xfffe], 5);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap().pc, 3);
+        assert_eq!(cpu.sp, 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg[Register::B], 5);
+        assert_eq!(cpu.reg[Register::C], 9);
+    }
+}
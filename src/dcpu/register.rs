@@ -1,7 +1,8 @@
+use std::fmt;
 use std::str::FromStr;
 use enum_map::{Enum};
 
-#[derive(Debug, Enum, Copy, Clone)]
+#[derive(Debug, Enum, Copy, Clone, PartialEq)]
 pub enum Register {
     A,
     B,
@@ -45,7 +46,7 @@ impl Register {
 impl FromStr for Register {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err>{
-        match s {
+        match s.to_lowercase().as_str() {
             "a" => Ok(Register::A),
             "b" => Ok(Register::B),
             "c" => Ok(Register::C),
@@ -58,3 +59,18 @@ impl FromStr for Register {
         }
     }
 }
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Register::A => "a",
+            Register::B => "b",
+            Register::C => "c",
+            Register::X => "x",
+            Register::Y => "y",
+            Register::Z => "z",
+            Register::I => "i",
+            Register::J => "j"
+        })
+    }
+}
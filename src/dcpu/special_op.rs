@@ -1,6 +1,7 @@
+use std::fmt;
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum SpecialOp {
     JSR,
     INT,
@@ -63,7 +64,7 @@ impl SpecialOp {
 impl FromStr for SpecialOp {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
+        match s.to_lowercase().as_str() {
             "jsr" => Ok(SpecialOp::JSR),
             "int" => Ok(SpecialOp::INT),
             "iag" => Ok(SpecialOp::IAG),
@@ -77,3 +78,19 @@ impl FromStr for SpecialOp {
         }
     }
 }
+
+impl fmt::Display for SpecialOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SpecialOp::JSR => "jsr",
+            SpecialOp::INT => "int",
+            SpecialOp::IAG => "iag",
+            SpecialOp::IAS => "ias",
+            SpecialOp::RFI => "rfi",
+            SpecialOp::IAQ => "iaq",
+            SpecialOp::HWN => "hwn",
+            SpecialOp::HWQ => "hwq",
+            SpecialOp::HWI => "hwi"
+        })
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Where an instruction came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: usize
+}
+
+// Label addresses and the source line of every instruction. Symbol files
+// are text, one entry per line:
+//
+// label 0005 loop
+// line 0005 6 main.rs
+//
+// Lines starting with `;` are comments. File names are the rest of the
+// line, so they can contain spaces.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolMap {
+    pub labels: BTreeMap<String, u16>,
+    pub lines: BTreeMap<u16, SourceLine>
+}
+
+impl SymbolMap {
+    pub fn new() -> SymbolMap {
+        SymbolMap::default()
+    }
+
+    pub fn address_of(&self, label: &str) -> Option<u16> {
+        self.labels.get(label).copied()
+    }
+
+    // Closest label at or before `address` and the offset from it, so that
+    // an address can be shown as `loop+2`
+    pub fn label_before(&self, address: u16) -> Option<(&str, u16)> {
+        self.labels.iter()
+            .filter(|(_, at)| **at <= address)
+            .max_by_key(|(_, at)| **at)
+            .map(|(label, at)| (label.as_str(), address - at))
+    }
+
+    pub fn line_at(&self, address: u16) -> Option<&SourceLine> {
+        self.lines.get(&address)
+    }
+
+    pub fn parse(text: &str) -> io::Result<SymbolMap> {
+        let mut symbols = SymbolMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, what));
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let mut fields = line.splitn(3, ' ');
+            let kind = fields.next().unwrap_or("");
+            let address = fields.next()
+                .and_then(|address| u16::from_str_radix(address, 16).ok())
+                .ok_or_else(|| invalid("expected a hex address"))?;
+            let rest = fields.next().map(str::trim).unwrap_or("");
+            match kind {
+                "label" if !rest.is_empty() => {
+                    symbols.labels.insert(String::from(rest), address);
+                },
+                "line" => {
+                    let mut fields = rest.splitn(2, ' ');
+                    let line = fields.next()
+                        .and_then(|line| line.parse().ok())
+                        .ok_or_else(|| invalid("expected a line number"))?;
+                    let file = fields.next().map(str::trim).unwrap_or("");
+                    symbols.lines.insert(address, SourceLine { file: String::from(file), line });
+                },
+                "label" => return Err(invalid("expected a label")),
+                kind => return Err(invalid(&format!("unknown entry `{}`", kind)))
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SymbolMap> {
+        SymbolMap::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+}
+
+// Labels sorted by address, then instructions
+impl fmt::Display for SymbolMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        labels.sort_by_key(|(label, address)| (**address, label.as_str()));
+        for (label, address) in labels {
+            writeln!(f, "label {:04x} {}", address, label)?;
+        }
+        for (address, source) in &self.lines {
+            writeln!(f, "line {:04x} {} {}", address, source.line, source.file)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_files_are_parsed() {
+        let map = SymbolMap::parse("; comment\n\nlabel 0010 start\nline 0010 3 dir/my file.s\nlabel 0012 start.loop\n").unwrap();
+        assert_eq!(map.address_of("start.loop"), Some(0x12));
+        assert_eq!(map.line_at(0x10), Some(&SourceLine { file: String::from("dir/my file.s"), line: 3 }));
+        assert_eq!(map.label_before(0x15), Some(("start.loop", 3)));
+        assert_eq!(map.label_before(0x0f), None);
+        assert_eq!(map.to_string(), "label 0010 start\nlabel 0012 start.loop\nline 0010 3 dir/my file.s\n");
+    }
+
+    #[test]
+    fn malformed_entries_are_errors() {
+        for text in ["label start", "label 0010", "line 0010 x main.s", "word 0010 1"] {
+            assert_eq!(SymbolMap::parse(text).unwrap_err().kind(), io::ErrorKind::InvalidData, "{}", text);
+        }
+    }
+}
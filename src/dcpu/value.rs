@@ -1,13 +1,13 @@
 use crate::dcpu::{Register};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Reg(Register), // register (A, B, C, X, Y, Z, I or J, in that order)
     DerefReg(Register), // [register]
     IndexReg(Register, u16), // [register + next word]
     STACK, // (PUSH / [--SP]) if in b, or (POP / [SP++]) if in a
     PEEK, // [SP] / PEEK
-    PICK, // [SP + next word] / PICK n
+    PICK(u16), // [SP + next word] / PICK n
     SP,
     PC,
     EX,
@@ -22,15 +22,15 @@ impl Value {
             if let Some(reg) = Register::new(val) {
                 return Some(Value::Reg(reg));
             }
-        } else if 0x08 <= val && val <= 0x0f {
+        } else if (0x08..=0x0f).contains(&val) {
             if let Some(reg) = Register::new(val - 0x08) {
                 return Some(Value::DerefReg(reg));
             }
-        } else if 0x10 <= val && val <= 0x17 {
+        } else if (0x10..=0x17).contains(&val) {
             if let Some(reg) = Register::new(val - 0x10) {
                 return Some(Value::IndexReg(reg, 0));
             }
-        } else if 0x20 <= val && val <= 0x3f {
+        } else if (0x20..=0x3f).contains(&val) {
             let literal = val
                 .wrapping_add(0xffff)
                 .wrapping_sub(0x20);
@@ -39,7 +39,7 @@ impl Value {
         match val {
             0x18 => Some(Value::STACK),
             0x19 => Some(Value::PEEK),
-            0x1a => Some(Value::PICK),
+            0x1a => Some(Value::PICK(0)),
             0x1b => Some(Value::SP),
             0x1c => Some(Value::PC),
             0x1d => Some(Value::EX),
@@ -49,6 +49,12 @@ impl Value {
         }
     }
 
+    // Whether the operand with this code is followed by a word, works for
+    // any code so that data can be skipped without decoding it
+    pub fn has_next_word(code: u16) -> bool {
+        matches!(code, 0x10..=0x17 | 0x1a | 0x1e | 0x1f)
+    }
+
     pub fn code(&self) -> u16 {
         match self {
             Value::Reg(reg) => reg.code(),
@@ -56,7 +62,7 @@ impl Value {
             Value::IndexReg(reg, _) => 0x10 + reg.code(),
             Value::STACK => 0x18,
             Value::PEEK => 0x19,
-            Value::PICK => 0x1a,
+            Value::PICK(_) => 0x1a,
             Value::SP => 0x1b,
             Value::PC => 0x1c,
             Value::EX => 0x1d,
@@ -77,12 +83,12 @@ impl Value {
             Value::IndexReg(_, _) => 1,
             Value::STACK => 0,
             Value::PEEK => 0,
-            Value::PICK => 1,
+            Value::PICK(_) => 1,
             Value::SP => 0,
             Value::PC => 0,
             Value::EX => 0,
             Value::DerefNextWord(_) => 1,
-            Value::NextWord(_) => 0,
+            Value::NextWord(_) => 1,
             Value::Literal(_) => 0
         }
     }
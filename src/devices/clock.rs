@@ -0,0 +1,204 @@
+use crate::dcpu::{DCPU16, Hardware, Register, CPU_FREQUENCY};
+
+// Generic Clock (compatible)
+//
+// Ticks are computed from the cycles the DCPU has executed rather than
+// wall clock time, so timing only depends on the program.
+#[derive(Debug, Default)]
+pub struct Clock {
+    rate: u16,
+    cycles: usize,
+    ticks: usize,
+    interrupt_message: u16
+}
+
+impl Clock {
+    // Interrupt messages (value of register A on HWI)
+    pub const SET_RATE: u16 = 0;
+    pub const GET_TICKS: u16 = 1;
+    pub const SET_INTERRUPT: u16 = 2;
+
+    // Ticks per second at rate 1
+    pub const BASE_FREQUENCY: usize = 60;
+
+    pub fn new() -> Clock {
+        Clock::default()
+    }
+
+    pub fn rate(&self) -> u16 {
+        self.rate
+    }
+
+    // Ticks since the last SET_RATE
+    pub fn ticks(&self) -> usize {
+        self.ticks
+    }
+}
+
+impl Hardware for Clock {
+    fn id(&self) -> u32 {
+        0x12d0b402
+    }
+
+    fn version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0
+    }
+
+    fn interrupt(&mut self, cpu: &mut DCPU16) -> Result<usize, String> {
+        let b = cpu.reg[Register::B];
+        match cpu.reg[Register::A] {
+            Clock::SET_RATE => {
+                self.rate = b;
+                self.cycles = 0;
+                self.ticks = 0;
+            },
+            Clock::GET_TICKS => {
+                cpu.reg[Register::C] = self.ticks as u16;
+            },
+            Clock::SET_INTERRUPT => {
+                self.interrupt_message = b;
+            },
+            _ => {}
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, cpu: &mut DCPU16, cycles: usize) {
+        if self.rate == 0 {
+            return;
+        }
+        self.cycles += cycles;
+        // Computed from the total so that rates which don't divide the
+        // CPU frequency evenly don't drift.
+        let ticks = self.cycles * Clock::BASE_FREQUENCY / (self.rate as usize * CPU_FREQUENCY);
+        while self.ticks < ticks {
+            self.ticks += 1;
+            if self.interrupt_message != 0 {
+                cpu.interrupt(self.interrupt_message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Cycles until the first tick at rate 1, 100,000 / 60 rounded up
+    const CYCLES_PER_TICK: usize = CPU_FREQUENCY / Clock::BASE_FREQUENCY + 1;
+
+    // Sends an interrupt to the clock the way HWI does
+    fn send(clock: &mut Clock, cpu: &mut DCPU16, message: u16, b: u16) {
+        cpu.reg[Register::A] = message;
+        cpu.reg[Register::B] = b;
+        clock.interrupt(cpu).unwrap();
+    }
+
+    fn get_ticks(clock: &mut Clock, cpu: &mut DCPU16) -> u16 {
+        send(clock, cpu, Clock::GET_TICKS, 0);
+        cpu.reg[Register::C]
+    }
+
+    #[test]
+    fn ticks_follow_cycles_at_the_set_rate() {
+        let mut cpu = DCPU16::new();
+        let mut clock = Clock::new();
+        // Off until SET_RATE
+        clock.tick(&mut cpu, CPU_FREQUENCY);
+        assert_eq!(get_ticks(&mut clock, &mut cpu), 0);
+
+        send(&mut clock, &mut cpu, Clock::SET_RATE, 1);
+        clock.tick(&mut cpu, CYCLES_PER_TICK - 1);
+        assert_eq!(get_ticks(&mut clock, &mut cpu), 0);
+        clock.tick(&mut cpu, 1);
+        assert_eq!(get_ticks(&mut clock, &mut cpu), 1);
+        clock.tick(&mut cpu, CPU_FREQUENCY - CYCLES_PER_TICK);
+        assert_eq!(get_ticks(&mut clock, &mut cpu), 60);
+
+        // Rate 2 is 30 ticks a second, SET_RATE restarts the count
+        send(&mut clock, &mut cpu, Clock::SET_RATE, 2);
+        assert_eq!(get_ticks(&mut clock, &mut cpu), 0);
+        clock.tick(&mut cpu, CPU_FREQUENCY);
+        assert_eq!(get_ticks(&mut clock, &mut cpu), 30);
+
+        // Rate 0 turns the clock off
+        send(&mut clock, &mut cpu, Clock::SET_RATE, 0);
+        clock.tick(&mut cpu, CPU_FREQUENCY);
+        assert_eq!(get_ticks(&mut clock, &mut cpu), 0);
+    }
+
+    #[test]
+    fn ticks_come_from_cycles_not_wall_clock_time() {
+        let mut cpu = DCPU16::new();
+        let mut one_step = Clock::new();
+        let mut many_steps = Clock::new();
+        send(&mut one_step, &mut cpu, Clock::SET_RATE, 1);
+        send(&mut many_steps, &mut cpu, Clock::SET_RATE, 1);
+
+        one_step.tick(&mut cpu, 12_345);
+        for _ in 0..12_345 {
+            many_steps.tick(&mut cpu, 1);
+        }
+        assert_eq!(one_step.ticks(), 12_345 * 60 / CPU_FREQUENCY);
+        assert_eq!(many_steps.ticks(), one_step.ticks());
+
+        // Time passing without cycles changes nothing
+        thread::sleep(Duration::from_millis(50));
+        one_step.tick(&mut cpu, 0);
+        assert_eq!(one_step.ticks(), 12_345 * 60 / CPU_FREQUENCY);
+    }
+
+    #[test]
+    fn interrupts_fire_after_the_right_number_of_cycles() {
+        let program = assembly::parse("
+                ias handler
+                set a, 2        ; SET_INTERRUPT
+                set b, 7
+                hwi 0
+                set a, 0        ; SET_RATE
+                set b, 1
+                hwi 0
+            wait:
+                set pc, wait
+            handler:
+                set [0x1001], a
+                add [0x1000], 1
+                rfi 0
+        ").unwrap();
+        let code = assembly::generate_code(program).unwrap();
+        let mut cpu = DCPU16::new();
+        cpu.mem[..code.len()].copy_from_slice(&code);
+        let clock = Rc::new(RefCell::new(Clock::new()));
+        cpu.attach(Box::new(clock.clone()));
+
+        // Up to the HWI that sets the rate, its own cycles count
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        let started = cpu.cycles;
+        while cpu.mem[0x1000] == 0 {
+            cpu.step().unwrap();
+            assert!(cpu.cycles < started + 2 * CYCLES_PER_TICK, "no interrupt");
+        }
+        // Handled within a few instructions of the tick
+        let elapsed = cpu.cycles - started;
+        assert!((CYCLES_PER_TICK..CYCLES_PER_TICK + 16).contains(&elapsed), "{}", elapsed);
+        assert_eq!(cpu.mem[0x1001], 7);
+
+        // One interrupt per tick
+        cpu.run_cycles(CPU_FREQUENCY).unwrap();
+        let ticks = clock.borrow().ticks();
+        assert!(ticks >= 60);
+        assert!(ticks as u16 - cpu.mem[0x1000] <= 1);
+    }
+}
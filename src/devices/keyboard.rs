@@ -0,0 +1,257 @@
+use crate::dcpu::{DCPU16, Hardware, Register};
+
+use std::collections::VecDeque;
+
+// Key codes, printable ASCII characters (0x20-0x7f) map to themselves
+pub const KEY_BACKSPACE: u16 = 0x10;
+pub const KEY_RETURN: u16 = 0x11;
+pub const KEY_INSERT: u16 = 0x12;
+pub const KEY_DELETE: u16 = 0x13;
+pub const KEY_ARROW_UP: u16 = 0x80;
+pub const KEY_ARROW_DOWN: u16 = 0x81;
+pub const KEY_ARROW_LEFT: u16 = 0x82;
+pub const KEY_ARROW_RIGHT: u16 = 0x83;
+pub const KEY_SHIFT: u16 = 0x90;
+pub const KEY_CONTROL: u16 = 0x91;
+
+const BUFFER_SIZE: usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyEvent {
+    Press(u16),
+    Release(u16)
+}
+
+// Generic Keyboard (compatible)
+//
+// Key events are scheduled at a cycle count (counted from the moment the
+// keyboard is attached) and delivered when the DCPU gets there, so a
+// scripted session plays back identically on every run.
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    buffer: VecDeque<u16>,
+    pressed: Vec<u16>,
+    interrupt_message: u16,
+    cycles: usize,
+    script: VecDeque<(usize, KeyEvent)>
+}
+
+impl Keyboard {
+    // Interrupt messages (value of register A on HWI)
+    pub const CLEAR_BUFFER: u16 = 0;
+    pub const GET_NEXT: u16 = 1;
+    pub const CHECK_KEY: u16 = 2;
+    pub const SET_INTERRUPT: u16 = 3;
+
+    pub fn new() -> Keyboard {
+        Keyboard::default()
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // Schedules an event, events scheduled for the same cycle keep their order.
+    pub fn schedule(&mut self, cycle: usize, event: KeyEvent) {
+        let index = self.script.iter()
+            .position(|(at, _)| *at > cycle)
+            .unwrap_or(self.script.len());
+        self.script.insert(index, (cycle, event));
+    }
+
+    pub fn script<I: IntoIterator<Item = (usize, KeyEvent)>>(&mut self, events: I) {
+        for (cycle, event) in events {
+            self.schedule(cycle, event);
+        }
+    }
+
+    // Presses and releases the key at the given cycle
+    pub fn type_key(&mut self, cycle: usize, key: u16) {
+        self.schedule(cycle, KeyEvent::Press(key));
+        self.schedule(cycle, KeyEvent::Release(key));
+    }
+
+    // Types a string starting at the given cycle, one key every `spacing` cycles.
+    // '\n' is typed as Return.
+    pub fn type_text(&mut self, cycle: usize, text: &str, spacing: usize) {
+        for (i, c) in text.chars().enumerate() {
+            let key = match c {
+                '\n' => KEY_RETURN,
+                c => c as u16
+            };
+            self.type_key(cycle + i * spacing, key);
+        }
+    }
+
+    pub fn pending_events(&self) -> usize {
+        self.script.len()
+    }
+
+    fn apply(&mut self, cpu: &mut DCPU16, event: KeyEvent) {
+        match event {
+            KeyEvent::Press(key) => {
+                if !self.pressed.contains(&key) {
+                    self.pressed.push(key);
+                }
+                if self.buffer.len() < BUFFER_SIZE {
+                    self.buffer.push_back(key);
+                }
+            },
+            KeyEvent::Release(key) => {
+                self.pressed.retain(|pressed| *pressed != key);
+            }
+        }
+        if self.interrupt_message != 0 {
+            cpu.interrupt(self.interrupt_message);
+        }
+    }
+}
+
+impl Hardware for Keyboard {
+    fn id(&self) -> u32 {
+        0x30cf7406
+    }
+
+    fn version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0
+    }
+
+    fn interrupt(&mut self, cpu: &mut DCPU16) -> Result<usize, String> {
+        let b = cpu.reg[Register::B];
+        match cpu.reg[Register::A] {
+            Keyboard::CLEAR_BUFFER => {
+                self.buffer.clear();
+            },
+            Keyboard::GET_NEXT => {
+                cpu.reg[Register::C] = self.buffer.pop_front().unwrap_or(0);
+            },
+            Keyboard::CHECK_KEY => {
+                cpu.reg[Register::C] = if self.pressed.contains(&b) { 1 } else { 0 };
+            },
+            Keyboard::SET_INTERRUPT => {
+                self.interrupt_message = b;
+            },
+            _ => {}
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, cpu: &mut DCPU16, cycles: usize) {
+        self.cycles += cycles;
+        while let Some((at, event)) = self.script.front().copied() {
+            if at > self.cycles {
+                break;
+            }
+            self.script.pop_front();
+            self.apply(cpu, event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly;
+
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    // Loads the program with a keyboard attached, also returns its labels
+    fn boot(source: &str) -> (DCPU16, Rc<RefCell<Keyboard>>, HashMap<String, u16>) {
+        let program = assembly::parse(source).unwrap();
+        let labels = assembly::layout(&program, &assembly::Options::default()).unwrap().labels;
+        let code = assembly::generate_code(program).unwrap();
+        let mut cpu = DCPU16::new();
+        cpu.mem[..code.len()].copy_from_slice(&code);
+        let keyboard = Rc::new(RefCell::new(Keyboard::new()));
+        cpu.attach(Box::new(keyboard.clone()));
+        (cpu, keyboard, labels)
+    }
+
+    // Runs until the program stops at a `dat 0`
+    fn run(cpu: &mut DCPU16) {
+        while cpu.step().is_ok() {
+            assert!(cpu.cycles < 100_000, "program didn't stop");
+        }
+    }
+
+    #[test]
+    fn scripted_keys_fill_the_buffer() {
+        let (mut cpu, keyboard, _) = boot("
+                set i, 0x1000
+            next:
+                set a, 1        ; GET_NEXT
+                hwi 0
+                ife c, 0
+                set pc, next
+                sti [i], c
+                ifl i, 0x1003
+                set pc, next
+                set a, 2        ; CHECK_KEY
+                set b, 0x90
+                hwi 0
+                set [0x1003], c
+                dat 0
+        ");
+        keyboard.borrow_mut().type_text(100, "hi", 50);
+        keyboard.borrow_mut().schedule(120, KeyEvent::Press(KEY_SHIFT));
+        run(&mut cpu);
+        // Keys arrive in script order, shift is still held down
+        assert_eq!(cpu.mem[0x1000..0x1004], [0x68, KEY_SHIFT, 0x69, 1]);
+        assert!(keyboard.borrow().cycles() >= 150);
+        assert_eq!(keyboard.borrow().pending_events(), 0);
+    }
+
+    #[test]
+    fn key_events_raise_interrupts() {
+        let (mut cpu, keyboard, labels) = boot("
+                ias handler
+                set a, 3        ; SET_INTERRUPT
+                set b, 0x42
+                hwi 0
+            wait:
+                ifl [count], 2
+                set pc, wait
+                dat 0
+            handler:
+                set [message], a
+                add [count], 1
+                rfi 0
+            count:
+                dat 0
+            message:
+                dat 0
+        ");
+        // Press and release, one interrupt each
+        keyboard.borrow_mut().type_key(100, 'q' as u16);
+        run(&mut cpu);
+        assert_eq!(cpu.mem[labels["count"] as usize], 2);
+        assert_eq!(cpu.mem[labels["message"] as usize], 0x42);
+    }
+
+    #[test]
+    fn scripts_play_back_identically() {
+        let source = "
+            loop:
+                set a, 1
+                hwi 0
+                ife c, 0
+                set pc, loop
+                dat 0
+        ";
+        let finished_at = || {
+            let (mut cpu, keyboard, _) = boot(source);
+            keyboard.borrow_mut().type_key(500, 'x' as u16);
+            run(&mut cpu);
+            cpu.cycles
+        };
+        let first = finished_at();
+        assert!(first >= 500);
+        assert_eq!(first, finished_at());
+    }
+}
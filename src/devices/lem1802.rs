@@ -0,0 +1,285 @@
+use crate::dcpu::{DCPU16, Hardware, Register, CPU_FREQUENCY};
+use crate::devices::Framebuffer;
+
+pub const SCREEN_COLUMNS: usize = 32;
+pub const SCREEN_ROWS: usize = 12;
+pub const CELL_WIDTH: usize = 4;
+pub const CELL_HEIGHT: usize = 8;
+pub const SCREEN_WIDTH: usize = SCREEN_COLUMNS * CELL_WIDTH; // 128
+pub const SCREEN_HEIGHT: usize = SCREEN_ROWS * CELL_HEIGHT; // 96
+pub const BORDER_SIZE: usize = 8;
+
+// Blinking characters toggle every half second
+const BLINK_PERIOD: usize = CPU_FREQUENCY / 2;
+
+pub const DEFAULT_PALETTE: [u16; 16] = [
+    0x0000, 0x000a, 0x00a0, 0x00aa, 0x0a00, 0x0a0a, 0x0a50, 0x0aaa,
+    0x0555, 0x055f, 0x05f5, 0x05ff, 0x0f55, 0x0f5f, 0x0ff5, 0x0fff
+];
+
+// Two words per glyph, each word holds two columns of 8 pixels (high byte
+// first), bit 0 is the top row.
+pub const DEFAULT_FONT: [u16; 256] = [
+    0xb79e, 0x388e, 0x722c, 0x75f4, 0x19bb, 0x7f8f, 0x85f9, 0xb158,
+    0x242e, 0x2400, 0x082a, 0x0800, 0x0008, 0x0000, 0x0808, 0x0808,
+    0x00ff, 0x0000, 0x00f8, 0x0808, 0xf808, 0x0000, 0x080f, 0x0000,
+    0x000f, 0x0808, 0x00ff, 0x0808, 0x08f8, 0x0808, 0x08ff, 0x0000,
+    0x080f, 0x0808, 0x08ff, 0x0808, 0x6633, 0x99cc, 0x9933, 0x66cc,
+    0xfef8, 0xe080, 0x7f1f, 0x0701, 0x0107, 0x1f7f, 0x80e0, 0xf8fe,
+    0x5500, 0xaa00, 0x55aa, 0x55aa, 0xffaa, 0xff55, 0x0f0f, 0x0f0f,
+    0xf0f0, 0xf0f0, 0x0000, 0xffff, 0xffff, 0x0000, 0xffff, 0xffff,
+    0x0000, 0x0000, 0x005f, 0x0000, 0x0300, 0x0300, 0x3e14, 0x3e00,
+    0x266b, 0x3200, 0x611c, 0x4300, 0x3629, 0x7650, 0x0002, 0x0100,
+    0x1c22, 0x4100, 0x4122, 0x1c00, 0x1408, 0x1400, 0x081c, 0x0800,
+    0x4020, 0x0000, 0x0808, 0x0800, 0x0040, 0x0000, 0x601c, 0x0300,
+    0x3e49, 0x3e00, 0x427f, 0x4000, 0x6259, 0x4600, 0x2249, 0x3600,
+    0x0f08, 0x7f00, 0x2745, 0x3900, 0x3e49, 0x3200, 0x6119, 0x0700,
+    0x3649, 0x3600, 0x2649, 0x3e00, 0x0024, 0x0000, 0x4024, 0x0000,
+    0x0814, 0x2200, 0x1414, 0x1400, 0x2214, 0x0800, 0x0259, 0x0600,
+    0x3e59, 0x5e00, 0x7e09, 0x7e00, 0x7f49, 0x3600, 0x3e41, 0x2200,
+    0x7f41, 0x3e00, 0x7f49, 0x4100, 0x7f09, 0x0100, 0x3e41, 0x7a00,
+    0x7f08, 0x7f00, 0x417f, 0x4100, 0x2040, 0x3f00, 0x7f08, 0x7700,
+    0x7f40, 0x4000, 0x7f06, 0x7f00, 0x7f01, 0x7e00, 0x3e41, 0x3e00,
+    0x7f09, 0x0600, 0x3e61, 0x7e00, 0x7f09, 0x7600, 0x2649, 0x3200,
+    0x017f, 0x0100, 0x3f40, 0x7f00, 0x1f60, 0x1f00, 0x7f30, 0x7f00,
+    0x7708, 0x7700, 0x0778, 0x0700, 0x7149, 0x4700, 0x007f, 0x4100,
+    0x031c, 0x6000, 0x417f, 0x0000, 0x0201, 0x0200, 0x8080, 0x8000,
+    0x0001, 0x0200, 0x2454, 0x7800, 0x7f44, 0x3800, 0x3844, 0x2800,
+    0x3844, 0x7f00, 0x3854, 0x5800, 0x087e, 0x0900, 0x4854, 0x3c00,
+    0x7f04, 0x7800, 0x047d, 0x0000, 0x2040, 0x3d00, 0x7f10, 0x6c00,
+    0x017f, 0x0000, 0x7c18, 0x7c00, 0x7c04, 0x7800, 0x3844, 0x3800,
+    0x7c14, 0x0800, 0x0814, 0x7c00, 0x7c04, 0x0800, 0x4854, 0x2400,
+    0x043e, 0x4400, 0x3c40, 0x7c00, 0x1c60, 0x1c00, 0x7c30, 0x7c00,
+    0x6c10, 0x6c00, 0x4c50, 0x3c00, 0x6454, 0x4c00, 0x0836, 0x4100,
+    0x0077, 0x0000, 0x4136, 0x0800, 0x0201, 0x0201, 0x0205, 0x0200
+];
+
+// NYA_ELEKTRISKA LEM1802 - Low Energy Monitor
+#[derive(Debug, Default)]
+pub struct LEM1802 {
+    screen: u16,
+    font: u16,
+    palette: u16,
+    border: u16,
+    cycles: usize
+}
+
+impl LEM1802 {
+    // Interrupt messages (value of register A on HWI)
+    pub const MEM_MAP_SCREEN: u16 = 0;
+    pub const MEM_MAP_FONT: u16 = 1;
+    pub const MEM_MAP_PALETTE: u16 = 2;
+    pub const SET_BORDER_COLOR: u16 = 3;
+    pub const MEM_DUMP_FONT: u16 = 4;
+    pub const MEM_DUMP_PALETTE: u16 = 5;
+
+    pub fn new() -> LEM1802 {
+        LEM1802::default()
+    }
+
+    pub fn screen(&self) -> u16 {
+        self.screen
+    }
+
+    pub fn border_color(&self) -> u16 {
+        self.border
+    }
+
+    pub fn blink_on(&self) -> bool {
+        self.cycles < BLINK_PERIOD
+    }
+
+    // Renders the screen with a border of BORDER_SIZE pixels on each side.
+    // A disconnected screen (no VRAM mapped) is rendered black.
+    pub fn render(&self, cpu: &DCPU16) -> Framebuffer {
+        let width = SCREEN_WIDTH + 2 * BORDER_SIZE;
+        let height = SCREEN_HEIGHT + 2 * BORDER_SIZE;
+        let mut frame = Framebuffer::new(width, height);
+        if self.screen == 0 {
+            return frame;
+        }
+
+        let border = self.color(cpu, self.border);
+        for y in 0..height {
+            for x in 0..width {
+                frame.set_pixel(x, y, border);
+            }
+        }
+
+        for row in 0..SCREEN_ROWS {
+            for column in 0..SCREEN_COLUMNS {
+                let offset = (row * SCREEN_COLUMNS + column) as u16;
+                let cell = cpu.mem[self.screen.wrapping_add(offset) as usize];
+                let fg = self.color(cpu, cell >> 12);
+                let bg = self.color(cpu, (cell >> 8) & 0xf);
+                let blink = cell & 0x80 != 0;
+                let visible = !blink || self.blink_on();
+                let glyph = self.glyph(cpu, cell & 0x7f);
+                for (gx, bits) in glyph.iter().enumerate() {
+                    for gy in 0..CELL_HEIGHT {
+                        let lit = visible && bits & (1 << gy) != 0;
+                        let x = BORDER_SIZE + column * CELL_WIDTH + gx;
+                        let y = BORDER_SIZE + row * CELL_HEIGHT + gy;
+                        frame.set_pixel(x, y, if lit { fg } else { bg });
+                    }
+                }
+            }
+        }
+        frame
+    }
+
+    // Returns the four 8-pixel columns of a character
+    fn glyph(&self, cpu: &DCPU16, character: u16) -> [u8; 4] {
+        let (first, second) = if self.font == 0 {
+            let i = (character * 2) as usize;
+            (DEFAULT_FONT[i], DEFAULT_FONT[i + 1])
+        } else {
+            let address = self.font.wrapping_add(character * 2);
+            (cpu.mem[address as usize], cpu.mem[address.wrapping_add(1) as usize])
+        };
+        [(first >> 8) as u8, first as u8, (second >> 8) as u8, second as u8]
+    }
+
+    fn color(&self, cpu: &DCPU16, index: u16) -> (u8, u8, u8) {
+        let index = index & 0xf;
+        let color = if self.palette == 0 {
+            DEFAULT_PALETTE[index as usize]
+        } else {
+            cpu.mem[self.palette.wrapping_add(index) as usize]
+        };
+        // Scale 4 bit channels to 8 bits
+        let r = ((color >> 8) & 0xf) as u8 * 17;
+        let g = ((color >> 4) & 0xf) as u8 * 17;
+        let b = (color & 0xf) as u8 * 17;
+        (r, g, b)
+    }
+}
+
+impl Hardware for LEM1802 {
+    fn id(&self) -> u32 {
+        0x7349f615
+    }
+
+    fn version(&self) -> u16 {
+        0x1802
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1c6c8b36
+    }
+
+    fn interrupt(&mut self, cpu: &mut DCPU16) -> Result<usize, String> {
+        let b = cpu.reg[Register::B];
+        let cycles = match cpu.reg[Register::A] {
+            LEM1802::MEM_MAP_SCREEN => {
+                self.screen = b;
+                0
+            },
+            LEM1802::MEM_MAP_FONT => {
+                self.font = b;
+                0
+            },
+            LEM1802::MEM_MAP_PALETTE => {
+                self.palette = b;
+                0
+            },
+            LEM1802::SET_BORDER_COLOR => {
+                self.border = b & 0xf;
+                0
+            },
+            LEM1802::MEM_DUMP_FONT => {
+                for (i, word) in DEFAULT_FONT.iter().enumerate() {
+                    cpu.mem[b.wrapping_add(i as u16) as usize] = *word;
+                }
+                256
+            },
+            LEM1802::MEM_DUMP_PALETTE => {
+                for (i, word) in DEFAULT_PALETTE.iter().enumerate() {
+                    cpu.mem[b.wrapping_add(i as u16) as usize] = *word;
+                }
+                16
+            },
+            _ => 0
+        };
+        Ok(cycles)
+    }
+
+    fn tick(&mut self, _cpu: &mut DCPU16, cycles: usize) {
+        self.cycles = (self.cycles + cycles) % (2 * BLINK_PERIOD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembly;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Runs `source` until it stops at a `dat 0`
+    fn run(source: &str) -> (DCPU16, Rc<RefCell<LEM1802>>) {
+        let code = assembly::parse(source).and_then(assembly::generate_code).unwrap();
+        let mut cpu = DCPU16::new();
+        cpu.mem[..code.len()].copy_from_slice(&code);
+        let lem = Rc::new(RefCell::new(LEM1802::new()));
+        cpu.attach(Box::new(lem.clone()));
+        while cpu.step().is_ok() {}
+        (cpu, lem)
+    }
+
+    // The cell at `column`, `row` as text, `#` for foreground pixels
+    fn snapshot(frame: &Framebuffer, column: usize, row: usize, fg: (u8, u8, u8)) -> Vec<String> {
+        (0..CELL_HEIGHT)
+            .map(|y| (0..CELL_WIDTH)
+                .map(|x| {
+                    let pixel = frame.pixel(BORDER_SIZE + column * CELL_WIDTH + x, BORDER_SIZE + row * CELL_HEIGHT + y);
+                    if pixel == fg { '#' } else { '.' }
+                })
+                .collect())
+            .collect()
+    }
+
+    const PROGRAM: &str = "
+        set a, 0        ; MEM_MAP_SCREEN
+        set b, 0x8000
+        hwi 0
+        set a, 3        ; SET_BORDER_COLOR
+        set b, 4
+        hwi 0
+        set [0x8000], 0xf041    ; white A on black
+        set [0x8001], 0xf0c1    ; blinking
+        dat 0
+    ";
+
+    const A: [&str; 8] = [".#..", "#.#.", "#.#.", "###.", "#.#.", "#.#.", "#.#.", "...."];
+
+    #[test]
+    fn renders_default_font_and_border() {
+        let (cpu, lem) = run(PROGRAM);
+        let frame = lem.borrow().render(&cpu);
+        assert_eq!(frame.width, SCREEN_WIDTH + 2 * BORDER_SIZE);
+        assert_eq!(frame.height, SCREEN_HEIGHT + 2 * BORDER_SIZE);
+        assert_eq!(snapshot(&frame, 0, 0, (255, 255, 255)), A);
+        assert_eq!(frame.pixel(0, 0), (0xaa, 0, 0));
+        // Empty cells use color 0 for both
+        assert_eq!(frame.pixel(BORDER_SIZE + 10 * CELL_WIDTH, BORDER_SIZE), (0, 0, 0));
+    }
+
+    #[test]
+    fn blinking_cells_toggle() {
+        let (mut cpu, lem) = run(PROGRAM);
+        assert_eq!(snapshot(&lem.borrow().render(&cpu), 1, 0, (255, 255, 255)), A);
+        lem.borrow_mut().tick(&mut cpu, BLINK_PERIOD);
+        let frame = lem.borrow().render(&cpu);
+        assert_eq!(snapshot(&frame, 1, 0, (255, 255, 255)), vec!["...."; 8]);
+        assert_eq!(snapshot(&frame, 0, 0, (255, 255, 255)), A);
+    }
+
+    #[test]
+    fn disconnected_screen_is_black() {
+        let cpu = DCPU16::new();
+        let frame = LEM1802::new().render(&cpu);
+        assert_eq!(frame.pixel(0, 0), (0, 0, 0));
+    }
+}
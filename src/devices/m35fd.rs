@@ -0,0 +1,451 @@
+use crate::dcpu::{DCPU16, Hardware, Register, CPU_FREQUENCY};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const SECTOR_SIZE: usize = 512;
+pub const SECTORS_PER_TRACK: usize = 18;
+pub const TRACKS: usize = 80;
+pub const SECTORS: usize = SECTORS_PER_TRACK * TRACKS; // 1440
+pub const DISK_SIZE: usize = SECTORS * SECTOR_SIZE; // 737,280 words
+// Image file sizes, see ImageFormat
+pub const BYTE_IMAGE_SIZE: usize = DISK_SIZE; // 737,280 bytes
+pub const WORD_IMAGE_SIZE: usize = DISK_SIZE * 2; // 1,474,560 bytes
+
+// 2.4 ms per track
+const SEEK_CYCLES_PER_TRACK: usize = CPU_FREQUENCY * 24 / 10_000;
+// 30700 words per second
+const WORDS_PER_SECOND: usize = 30_700;
+
+// How a disk is stored in an image file. The format is picked from the
+// length of the file, files of any other length are rejected rather than
+// padded or truncated.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    // 737,280 bytes, one byte per word. This is the usual format for disks
+    // shipped next to ROMs, words have to fit in a byte to be saved this way.
+    Bytes,
+    // 1,474,560 bytes, every word big-endian
+    Words
+}
+
+// Floppy disk media, saved in the format it was loaded from. New disks are
+// saved as words.
+#[derive(Debug, Clone)]
+pub struct Disk {
+    pub data: Vec<u16>,
+    pub write_protected: bool,
+    pub format: ImageFormat,
+    path: Option<PathBuf>
+}
+
+impl Disk {
+    pub fn new() -> Disk {
+        Disk {
+            data: vec![0; DISK_SIZE],
+            write_protected: false,
+            format: ImageFormat::Words,
+            path: None
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Disk> {
+        let mut disk = Disk::new();
+        match bytes.len() {
+            BYTE_IMAGE_SIZE => {
+                disk.format = ImageFormat::Bytes;
+                for (word, byte) in disk.data.iter_mut().zip(bytes) {
+                    *word = *byte as u16;
+                }
+            },
+            WORD_IMAGE_SIZE => {
+                for (word, pair) in disk.data.iter_mut().zip(bytes.chunks(2)) {
+                    *word = u16::from_be_bytes([pair[0], pair[1]]);
+                }
+            },
+            length => {
+                let message = format!("disk image is {} bytes, expected {} or {}", length, BYTE_IMAGE_SIZE, WORD_IMAGE_SIZE);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+        Ok(disk)
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        match self.format {
+            ImageFormat::Bytes => self.data.iter()
+                .enumerate()
+                .map(|(address, word)| match *word {
+                    0..=0xff => Ok(*word as u8),
+                    _ => {
+                        let message = format!("word {:#06x} at {} doesn't fit in a byte image", word, address);
+                        Err(io::Error::new(io::ErrorKind::InvalidData, message))
+                    }
+                })
+                .collect(),
+            ImageFormat::Words => Ok(self.data.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect())
+        }
+    }
+
+    // Loads an image, the disk remembers the path so it can be flushed back.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Disk> {
+        let mut disk = Disk::from_bytes(&fs::read(path.as_ref())?)?;
+        disk.path = Some(path.as_ref().to_path_buf());
+        Ok(disk)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes()?)
+    }
+
+    // Writes the disk back to the image it was opened from
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => self.save(path),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "disk was not opened from a file"))
+        }
+    }
+
+    pub fn sector(&self, sector: u16) -> &[u16] {
+        let start = sector as usize * SECTOR_SIZE;
+        &self.data[start..start + SECTOR_SIZE]
+    }
+}
+
+impl Default for Disk {
+    fn default() -> Disk {
+        Disk::new()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Operation {
+    Read { sector: u16, address: u16 },
+    Write { sector: u16, address: u16 }
+}
+
+// Mackapar 3.5" Floppy Drive (M35FD)
+#[derive(Debug, Default)]
+pub struct M35FD {
+    disk: Option<Disk>,
+    error: u16,
+    track: usize,
+    operation: Option<(Operation, usize)>,
+    interrupt_message: u16,
+    changed: bool
+}
+
+impl M35FD {
+    // Interrupt messages (value of register A on HWI)
+    pub const POLL_DEVICE: u16 = 0;
+    pub const SET_INTERRUPT: u16 = 1;
+    pub const READ_SECTOR: u16 = 2;
+    pub const WRITE_SECTOR: u16 = 3;
+
+    pub const STATE_NO_MEDIA: u16 = 0;
+    pub const STATE_READY: u16 = 1;
+    pub const STATE_READY_WP: u16 = 2;
+    pub const STATE_BUSY: u16 = 3;
+
+    pub const ERROR_NONE: u16 = 0;
+    pub const ERROR_BUSY: u16 = 1;
+    pub const ERROR_NO_MEDIA: u16 = 2;
+    pub const ERROR_PROTECTED: u16 = 3;
+    pub const ERROR_EJECT: u16 = 4;
+    pub const ERROR_BAD_SECTOR: u16 = 5;
+    pub const ERROR_BROKEN: u16 = 0xffff;
+
+    pub fn new() -> M35FD {
+        M35FD::default()
+    }
+
+    pub fn insert(&mut self, disk: Disk) -> Option<Disk> {
+        let old = self.eject();
+        self.disk = Some(disk);
+        self.changed = true;
+        old
+    }
+
+    // Ejecting the disk in the middle of an operation aborts it
+    pub fn eject(&mut self) -> Option<Disk> {
+        if self.operation.take().is_some() {
+            self.set_error(M35FD::ERROR_EJECT);
+        }
+        let disk = self.disk.take();
+        if disk.is_some() {
+            self.changed = true;
+        }
+        disk
+    }
+
+    pub fn disk(&self) -> Option<&Disk> {
+        self.disk.as_ref()
+    }
+
+    pub fn disk_mut(&mut self) -> Option<&mut Disk> {
+        self.disk.as_mut()
+    }
+
+    pub fn state(&self) -> u16 {
+        match &self.disk {
+            None => M35FD::STATE_NO_MEDIA,
+            Some(_) if self.operation.is_some() => M35FD::STATE_BUSY,
+            Some(disk) if disk.write_protected => M35FD::STATE_READY_WP,
+            Some(_) => M35FD::STATE_READY
+        }
+    }
+
+    pub fn error(&self) -> u16 {
+        self.error
+    }
+
+    fn set_error(&mut self, error: u16) {
+        if self.error != error {
+            self.error = error;
+            self.changed = true;
+        }
+    }
+
+    // Checks whether an operation can be started and sets the error if not
+    fn check(&mut self, sector: u16, write: bool) -> bool {
+        let error = match &self.disk {
+            None => M35FD::ERROR_NO_MEDIA,
+            Some(_) if self.operation.is_some() => M35FD::ERROR_BUSY,
+            Some(_) if sector as usize >= SECTORS => M35FD::ERROR_BAD_SECTOR,
+            Some(disk) if write && disk.write_protected => M35FD::ERROR_PROTECTED,
+            Some(_) => return true
+        };
+        self.set_error(error);
+        false
+    }
+
+    fn start(&mut self, operation: Operation, sector: u16) {
+        let track = sector as usize / SECTORS_PER_TRACK;
+        let tracks = track.abs_diff(self.track);
+        let seek = tracks * SEEK_CYCLES_PER_TRACK;
+        let transfer = SECTOR_SIZE * CPU_FREQUENCY / WORDS_PER_SECOND;
+        self.track = track;
+        self.operation = Some((operation, seek + transfer));
+        self.changed = true;
+    }
+
+    fn finish(&mut self, cpu: &mut DCPU16, operation: Operation) {
+        if let Some(disk) = &mut self.disk {
+            match operation {
+                Operation::Read { sector, address } => {
+                    let start = sector as usize * SECTOR_SIZE;
+                    for i in 0..SECTOR_SIZE {
+                        cpu.mem[address.wrapping_add(i as u16) as usize] = disk.data[start + i];
+                    }
+                },
+                Operation::Write { sector, address } => {
+                    let start = sector as usize * SECTOR_SIZE;
+                    for i in 0..SECTOR_SIZE {
+                        disk.data[start + i] = cpu.mem[address.wrapping_add(i as u16) as usize];
+                    }
+                }
+            }
+        }
+        self.changed = true;
+    }
+}
+
+impl Hardware for M35FD {
+    fn id(&self) -> u32 {
+        0x4fd524c5
+    }
+
+    fn version(&self) -> u16 {
+        0x000b
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1eb37e91
+    }
+
+    fn interrupt(&mut self, cpu: &mut DCPU16) -> Result<usize, String> {
+        let x = cpu.reg[Register::X];
+        let y = cpu.reg[Register::Y];
+        match cpu.reg[Register::A] {
+            M35FD::POLL_DEVICE => {
+                cpu.reg[Register::B] = self.state();
+                cpu.reg[Register::C] = self.error;
+                self.error = M35FD::ERROR_NONE;
+            },
+            M35FD::SET_INTERRUPT => {
+                self.interrupt_message = x;
+            },
+            M35FD::READ_SECTOR => {
+                let started = self.check(x, false);
+                if started {
+                    self.start(Operation::Read { sector: x, address: y }, x);
+                }
+                cpu.reg[Register::B] = started as u16;
+            },
+            M35FD::WRITE_SECTOR => {
+                let started = self.check(x, true);
+                if started {
+                    self.start(Operation::Write { sector: x, address: y }, x);
+                }
+                cpu.reg[Register::B] = started as u16;
+            },
+            _ => {}
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, cpu: &mut DCPU16, cycles: usize) {
+        if let Some((operation, remaining)) = self.operation {
+            if remaining > cycles {
+                self.operation = Some((operation, remaining - cycles));
+            } else {
+                self.operation = None;
+                self.finish(cpu, operation);
+            }
+        }
+        // Interrupts are sent whenever the state or error changes
+        if self.changed {
+            self.changed = false;
+            if self.interrupt_message != 0 {
+                cpu.interrupt(self.interrupt_message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSFER_CYCLES: usize = SECTOR_SIZE * CPU_FREQUENCY / WORDS_PER_SECOND;
+
+    // Sends an interrupt to the drive the way HWI does
+    fn send(drive: &mut M35FD, cpu: &mut DCPU16, message: u16, x: u16, y: u16) {
+        cpu.reg[Register::A] = message;
+        cpu.reg[Register::X] = x;
+        cpu.reg[Register::Y] = y;
+        drive.interrupt(cpu).unwrap();
+    }
+
+    #[test]
+    fn read_is_busy_for_seek_and_transfer() {
+        let mut cpu = DCPU16::new();
+        let mut disk = Disk::new();
+        // First sector of track 2
+        let sector = 2 * SECTORS_PER_TRACK;
+        for (i, word) in disk.data[sector * SECTOR_SIZE..][..SECTOR_SIZE].iter_mut().enumerate() {
+            *word = i as u16 + 1;
+        }
+        let mut drive = M35FD::new();
+        drive.insert(disk);
+
+        send(&mut drive, &mut cpu, M35FD::READ_SECTOR, sector as u16, 0x1000);
+        assert_eq!(cpu.reg[Register::B], 1);
+        assert_eq!(drive.state(), M35FD::STATE_BUSY);
+
+        let busy = 2 * SEEK_CYCLES_PER_TRACK + TRANSFER_CYCLES;
+        drive.tick(&mut cpu, busy - 1);
+        assert_eq!(drive.state(), M35FD::STATE_BUSY);
+        assert_eq!(cpu.mem[0x1000], 0);
+
+        drive.tick(&mut cpu, 1);
+        assert_eq!(drive.state(), M35FD::STATE_READY);
+        assert_eq!(cpu.mem[0x1000], 1);
+        assert_eq!(cpu.mem[0x1000 + SECTOR_SIZE - 1], SECTOR_SIZE as u16);
+
+        // Same track again, no seek
+        send(&mut drive, &mut cpu, M35FD::READ_SECTOR, sector as u16 + 1, 0x1000);
+        drive.tick(&mut cpu, TRANSFER_CYCLES);
+        assert_eq!(drive.state(), M35FD::STATE_READY);
+    }
+
+    #[test]
+    fn write_is_busy_then_copies_memory() {
+        let mut cpu = DCPU16::new();
+        cpu.mem[0x2000..0x2000 + SECTOR_SIZE].iter_mut().for_each(|word| *word = 0xbeef);
+        let mut drive = M35FD::new();
+        drive.insert(Disk::new());
+
+        send(&mut drive, &mut cpu, M35FD::WRITE_SECTOR, 3, 0x2000);
+        assert_eq!(cpu.reg[Register::B], 1);
+        // A second operation is refused while the first one runs
+        send(&mut drive, &mut cpu, M35FD::READ_SECTOR, 4, 0x3000);
+        assert_eq!(cpu.reg[Register::B], 0);
+        send(&mut drive, &mut cpu, M35FD::POLL_DEVICE, 0, 0);
+        assert_eq!(cpu.reg[Register::B], M35FD::STATE_BUSY);
+        assert_eq!(cpu.reg[Register::C], M35FD::ERROR_BUSY);
+
+        drive.tick(&mut cpu, TRANSFER_CYCLES - 1);
+        assert!(drive.disk().unwrap().sector(3).iter().all(|word| *word == 0));
+        drive.tick(&mut cpu, 1);
+        assert!(drive.disk().unwrap().sector(3).iter().all(|word| *word == 0xbeef));
+        assert_eq!(drive.state(), M35FD::STATE_READY);
+    }
+
+    #[test]
+    fn write_protected_disks_refuse_writes() {
+        let mut cpu = DCPU16::new();
+        let mut drive = M35FD::new();
+        let mut disk = Disk::new();
+        disk.write_protected = true;
+        drive.insert(disk);
+
+        send(&mut drive, &mut cpu, M35FD::WRITE_SECTOR, 0, 0);
+        assert_eq!(cpu.reg[Register::B], 0);
+        assert_eq!(drive.state(), M35FD::STATE_READY_WP);
+        assert_eq!(drive.error(), M35FD::ERROR_PROTECTED);
+    }
+
+    #[test]
+    fn byte_images_hold_one_byte_per_word() {
+        let mut bytes = vec![0; BYTE_IMAGE_SIZE];
+        bytes[0] = 0x12;
+        bytes[BYTE_IMAGE_SIZE - 1] = 0xff;
+        let mut disk = Disk::from_bytes(&bytes).unwrap();
+        assert_eq!(disk.format, ImageFormat::Bytes);
+        assert_eq!(disk.data[0], 0x12);
+        assert_eq!(disk.data[DISK_SIZE - 1], 0xff);
+        assert_eq!(disk.to_bytes().unwrap(), bytes);
+
+        // Words that don't fit can't be saved in the same format
+        disk.data[3] = 0x100;
+        assert!(disk.to_bytes().is_err());
+        disk.format = ImageFormat::Words;
+        assert_eq!(disk.to_bytes().unwrap().len(), WORD_IMAGE_SIZE);
+    }
+
+    #[test]
+    fn flushing_keeps_the_image_format() {
+        let path = std::env::temp_dir().join(format!("m35fd-{}.img", std::process::id()));
+        fs::write(&path, vec![7; BYTE_IMAGE_SIZE]).unwrap();
+        let mut disk = Disk::open(&path).unwrap();
+        disk.data[0] = 0x41;
+        disk.flush().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), BYTE_IMAGE_SIZE);
+        assert_eq!(bytes[..2], [0x41, 7]);
+    }
+
+    #[test]
+    fn word_images_hold_big_endian_words() {
+        let mut bytes = vec![0; WORD_IMAGE_SIZE];
+        bytes[0] = 0x12;
+        bytes[1] = 0x34;
+        let disk = Disk::from_bytes(&bytes).unwrap();
+        assert_eq!(disk.format, ImageFormat::Words);
+        assert_eq!(disk.data[0], 0x1234);
+        assert_eq!(disk.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn images_of_other_sizes_are_rejected() {
+        let bytes = vec![0; WORD_IMAGE_SIZE + 1];
+        assert!(Disk::from_bytes(&bytes).is_err());
+        assert!(Disk::from_bytes(&bytes[..WORD_IMAGE_SIZE - 1]).is_err());
+        assert!(Disk::from_bytes(&bytes[..BYTE_IMAGE_SIZE - 1]).is_err());
+        assert!(Disk::from_bytes(&bytes[..BYTE_IMAGE_SIZE + 1]).is_err());
+        assert!(Disk::from_bytes(&[]).is_err());
+    }
+}
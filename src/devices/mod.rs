@@ -0,0 +1,11 @@
+mod clock;
+mod framebuffer;
+mod keyboard;
+mod lem1802;
+mod m35fd;
+
+pub use clock::*;
+pub use framebuffer::*;
+pub use keyboard::*;
+pub use lem1802::*;
+pub use m35fd::*;
@@ -0,0 +1,5 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod dcpu;
+pub mod assembly;
+pub mod devices;
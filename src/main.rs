@@ -1,71 +1,74 @@
-mod dcpu;
-mod assembly;
+use dcpu16::{dcpu, assembly};
 
 fn main() {
 
     let mut dcpu16 = dcpu::DCPU16::new();
     let mut rom: [u16; 0x10000] = [0x0000; 0x10000];
-    let arr = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-    let mut arr_addr = 1000;
-    rom[arr_addr] = arr.len() as u16;
-    for (i, x) in arr.iter().enumerate() {
-        rom[arr_addr + 1 + i] = *x;
-    }
-    let mut pc = 0;
 
-    let cmds = [
-        cmd(0x01, 0x01, 0x1f), // SET B, 1000
-        (arr_addr + 1) as u16,
-        cmd(0x01, 0x02, 0x1e), // SET C, [0x50]
-        arr_addr as u16,
-        cmd(0x02, 0x02, 0x01), // ADD, C, B
-        cmd(0x02, 0x00, 0x09), // ADD A, [B]
-        cmd(0x02, 0x01, 0x1f), // ADD B, 1
-        1,
-        cmd(0x13, 0x01, 0x02), // IFN B, C
-        cmd(0x00, 0x01, 0x1f), // JSR 0x04
-        4
-    ];
     let source_text =
 "
-set b, 1001;
-set c, [1000];
-add c, b;
-add a, [b];
-add b, 1;
-ifl b, c;
-jsr 5;
+; Sums the array at `array`, the first word is its length.
+        set b, array + 1
+        set c, [array]
+        add c, b            ; c = end of the array
+loop:   add a, [b]
+        add b, 1
+        ifl b, c
+        jsr loop
+; 0 is not a valid instruction, executing it stops the emulator
+end:    dat 0
+
+        .org 1000
+array:  dat 10, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10
 ";
-    let program = assembly::parse(source_text).unwrap();
-    // for command in program.iter() {
-    //     dbg!(command);
-    // }
-    let code = assembly::generate_code(program);
+    // --listing prints what every source line assembled to, --symbols
+    // prints the symbol map
+    let listing = std::env::args().any(|arg| arg == "--listing");
+    let symbols = std::env::args().any(|arg| arg == "--symbols");
+    let options = assembly::Options::default();
+    let code = match assembly::assemble_source("main.rs", source_text, &options).and_then(|code| {
+        if listing || symbols {
+            let program = assembly::parse_source("main.rs", source_text)?;
+            if listing {
+                print!("{}", assembly::listing(&program, &options)?);
+            }
+            if symbols {
+                print!("{}", assembly::symbols(&program, &options)?);
+            }
+        }
+        Ok(code)
+    }) {
+        Ok(code) => code,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}\n", error);
+            }
+            std::process::exit(1);
+        }
+    };
 
     for (i, word) in code.iter().enumerate() {
-        dbg!(word, cmds[i]);
         rom[i] = *word;
     }
 
-    // for (i, cmd) in cmds.iter().enumerate() {
-    //     dbg!(cmd);
-    //     rom[i] = *cmd;
-    // }
-
     dcpu16.load(rom);
-    loop {
-        let report = dcpu16.step();
-        match report {
-            Ok(pc) => {
-                dbg!(pc, dcpu16.reg);
+    // --trace prints the registers after every instruction
+    let trace = std::env::args().any(|arg| arg == "--trace");
+    let error = loop {
+        match dcpu16.step() {
+            Ok(report) => if trace {
+                println!("{:04x} {:>3}  {}", report.pc, report.cycles, registers(&dcpu16));
             },
-            Err(_) => {
-                break;
-            }
+            Err(error) => break error
         }
-    }
+    };
+    println!("stopped after {} cycles: {}", dcpu16.cycles, error);
+    println!("{}", registers(&dcpu16));
 }
 
-fn cmd(op: u16, b: u16, a: u16) -> u16 {
-    (a << 10) | (b << 5) | op
+fn registers(dcpu16: &dcpu::DCPU16) -> String {
+    let registers: Vec<String> = dcpu16.reg.iter()
+        .map(|(register, value)| format!("{}={:04x}", register, value))
+        .collect();
+    registers.join(" ")
 }